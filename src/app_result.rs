@@ -1,5 +1,6 @@
 use log::error;
 use rocket::{
+    http::Status,
     response::{Responder, Result as ResponseResult},
     serde::json::Json,
     Request,
@@ -11,6 +12,9 @@ pub(crate) struct AppResult<T: Serialize> {
     success: bool,
     obj: Option<T>,
     error: Option<String>,
+
+    #[serde(skip)]
+    status: Status,
 }
 
 impl<T: Serialize> AppResult<T> {
@@ -19,6 +23,7 @@ impl<T: Serialize> AppResult<T> {
             success: true,
             obj: Some(t),
             error: None,
+            status: Status::Ok,
         }
     }
 
@@ -27,8 +32,13 @@ impl<T: Serialize> AppResult<T> {
             success: false,
             obj: None,
             error: Some(e.to_string()),
+            status: Status::Ok,
         }
     }
+
+    pub(crate) fn with_status(self, status: Status) -> Self {
+        Self { status, ..self }
+    }
 }
 
 impl<T: Serialize, E: ToString> From<Result<T, E>> for AppResult<T> {
@@ -40,12 +50,22 @@ impl<T: Serialize, E: ToString> From<Result<T, E>> for AppResult<T> {
     }
 }
 
+/// Lets handlers that don't return JSON use `?` and still report errors as JSON.
+impl<T: Serialize> From<anyhow::Error> for AppResult<T> {
+    fn from(e: anyhow::Error) -> Self {
+        Self::error(format!("{:#}", e)).with_status(Status::InternalServerError)
+    }
+}
+
 impl<'r, T: Serialize> Responder<'r, 'static> for AppResult<T> {
     fn respond_to(self, request: &'r Request<'_>) -> ResponseResult<'static> {
         if let Some(err) = self.error.as_deref() {
             error!("Error: {}", err);
         }
 
-        Json(self).respond_to(request)
+        let status = self.status;
+        let mut response = Json(self).respond_to(request)?;
+        response.set_status(status);
+        Ok(response)
     }
 }
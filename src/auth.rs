@@ -0,0 +1,548 @@
+//! Pairing keeps other devices on the network out. The browser on this
+//! machine is trusted, other devices must enter the PIN shown in it to get
+//! a session token. Receivers can't pair or keep cookies, so media URLs
+//! sent to them carry a token signed for the file that expires. Devices
+//! that guess wrong must wait longer and longer before guessing again, the
+//! PIN itself stays the same until it is used, so guessing can't keep the
+//! owner from pairing.
+use crate::app_result::AppResult;
+use log::{info, warn};
+use rand::Rng;
+use ring::{hmac, rand::SystemRandom};
+use rocket::{
+    async_trait, catch, get,
+    http::{Cookie, CookieJar, SameSite, Status},
+    post,
+    request::{FromRequest, Outcome},
+    serde::json::Json,
+    Request, State,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+const SESSION_COOKIE: &str = "videocaster_session";
+
+/// How long media tokens are valid, long enough for a movie with breaks.
+const MEDIA_TOKEN_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How long a paired device stays paired.
+const SESSION_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How long a device must wait after its first wrong PIN, doubled for
+/// every wrong PIN after that.
+const LOCKOUT: Duration = Duration::from_secs(1);
+
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Devices that haven't guessed for this long after a lockout start over.
+const LOCKOUT_RESET: Duration = Duration::from_secs(60 * 60);
+
+const PIN_LEN: usize = 6;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) struct AuthStatus {
+    paired: bool,
+
+    /// Whether the client is the browser on this machine, which shows the PIN.
+    local: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PairRequest {
+    pin: String,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub(crate) enum PairError {
+    #[error("wrong PIN")]
+    WrongPin,
+
+    #[error("too many wrong PINs, try again in {} s", .0.as_secs().max(1))]
+    LockedOut(Duration),
+}
+
+impl<T: Serialize> From<PairError> for AppResult<T> {
+    fn from(err: PairError) -> Self {
+        let status = match err {
+            PairError::WrongPin => Status::Unauthorized,
+            PairError::LockedOut(_) => Status::TooManyRequests,
+        };
+
+        AppResult::error(err).with_status(status)
+    }
+}
+
+#[get("/auth/status")]
+pub(crate) async fn status(local: Option<Local>, paired: Option<Paired>) -> AppResult<AuthStatus> {
+    AppResult::success(AuthStatus {
+        paired: paired.is_some(),
+        local: local.is_some(),
+    })
+}
+
+#[get("/auth/pin")]
+pub(crate) async fn pin(_local: Local, auth: &State<Auth>) -> AppResult<String> {
+    AppResult::success(auth.pin())
+}
+
+/// Exchanges the PIN for a session token, which is also set as a cookie.
+/// Clients without cookies send it as a bearer token instead.
+#[post("/auth/pair", data = "<request>")]
+pub(crate) async fn pair(
+    request: Json<PairRequest>,
+    remote: SocketAddr,
+    cookies: &CookieJar<'_>,
+    auth: &State<Auth>,
+) -> AppResult<String> {
+    match auth.pair(&request.pin, remote.ip(), Instant::now()) {
+        Ok(token) => {
+            let cookie = Cookie::build(SESSION_COOKIE, token.clone())
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .permanent()
+                .finish();
+
+            cookies.add(cookie);
+            AppResult::success(token)
+        }
+        Err(err) => err.into(),
+    }
+}
+
+/// Signs a token for `subject`, i.e. a media ID or subtitles URL, which
+/// is added to media URLs as the `token` query parameter.
+#[get("/auth/token?<subject>")]
+pub(crate) async fn token(_paired: Paired, subject: &str, auth: &State<Auth>) -> AppResult<String> {
+    AppResult::success(auth.media_token(subject, SystemTime::now()))
+}
+
+#[catch(401)]
+pub(crate) fn unauthorized() -> AppResult<()> {
+    AppResult::error("this device must be paired first").with_status(Status::Unauthorized)
+}
+
+/// Sessions, the current PIN and the key media tokens are signed with.
+#[derive(Clone)]
+pub(crate) struct Auth {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// HMAC-SHA256 key media tokens are signed with, which is random, so
+    /// tokens are invalid after a restart.
+    key: hmac::Key,
+
+    /// Session tokens and when they expire.
+    sessions: Mutex<HashMap<String, Instant>>,
+    pin: Mutex<String>,
+    lockouts: Mutex<HashMap<IpAddr, Lockout>>,
+}
+
+fn new_pin() -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..PIN_LEN)
+        .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+        .collect()
+}
+
+/// Wrong PINs from one device and until when it must wait.
+struct Lockout {
+    failures: u32,
+    until: Instant,
+}
+
+impl Lockout {
+    fn fail(&mut self, now: Instant) {
+        self.failures += 1;
+        let delay = LOCKOUT
+            .checked_mul(1 << (self.failures - 1).min(31))
+            .map_or(MAX_LOCKOUT, |delay| delay.min(MAX_LOCKOUT));
+        self.until = now + delay;
+    }
+}
+
+impl Auth {
+    pub(crate) fn new() -> Self {
+        let key = hmac::Key::generate(hmac::HMAC_SHA256, &SystemRandom::new())
+            .expect("failed to generate media token key");
+
+        Self {
+            inner: Arc::new(Inner {
+                key,
+                sessions: Mutex::default(),
+                pin: Mutex::new(new_pin()),
+                lockouts: Mutex::default(),
+            }),
+        }
+    }
+
+    fn pin(&self) -> String {
+        self.inner.pin.lock().expect("pin").clone()
+    }
+
+    /// Returns a new session token if `pin` is right. The PIN can only be
+    /// used once. The device at `ip` can't guess again for a while after a
+    /// wrong guess.
+    fn pair(&self, pin: &str, ip: IpAddr, now: Instant) -> Result<String, PairError> {
+        let mut lockouts = self.inner.lockouts.lock().expect("lockouts");
+        lockouts.retain(|_, lockout| now < lockout.until + LOCKOUT_RESET);
+
+        if let Some(lockout) = lockouts.get(&ip).filter(|lockout| now < lockout.until) {
+            return Err(PairError::LockedOut(lockout.until - now));
+        }
+
+        let mut current = self.inner.pin.lock().expect("pin");
+
+        if pin.trim() != *current {
+            let lockout = lockouts.entry(ip).or_insert(Lockout {
+                failures: 0,
+                until: now,
+            });
+            lockout.fail(now);
+            warn!("wrong pin from {}, attempt {}", ip, lockout.failures);
+            return Err(PairError::WrongPin);
+        }
+
+        lockouts.remove(&ip);
+        *current = new_pin();
+        let token = format!("{:032x}", rand::random::<u128>());
+        let mut sessions = self.inner.sessions.lock().expect("sessions");
+        sessions.retain(|_, expires| now < *expires);
+        sessions.insert(token.clone(), now + SESSION_TTL);
+        info!("paired a new device");
+        Ok(token)
+    }
+
+    fn is_session(&self, token: &str, now: Instant) -> bool {
+        matches!(
+            self.inner.sessions.lock().expect("sessions").get(token),
+            Some(expires) if now < *expires
+        )
+    }
+
+    pub(crate) fn media_token(&self, subject: &str, now: SystemTime) -> String {
+        let expires = unix_secs(now + MEDIA_TOKEN_TTL);
+        format!("{}.{}", expires, self.sign(subject, expires))
+    }
+
+    fn verify(&self, subject: &str, token: &str, now: SystemTime) -> bool {
+        let (expires, tag) = match token.split_once('.') {
+            Some((expires, tag)) => (expires, tag),
+            None => return false,
+        };
+
+        let (expires, tag) = match (expires.parse::<u64>(), decode_hex(tag)) {
+            (Ok(expires), Some(tag)) => (expires, tag),
+            _ => return false,
+        };
+
+        // compares in constant time
+        expires > unix_secs(now)
+            && hmac::verify(&self.inner.key, &message(subject, expires), &tag).is_ok()
+    }
+
+    fn sign(&self, subject: &str, expires: u64) -> String {
+        hmac::sign(&self.inner.key, &message(subject, expires))
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+}
+
+/// What is signed, the expiry has no dots, so it can't run into the subject.
+fn message(subject: &str, expires: u64) -> Vec<u8> {
+    format!("{}.{}", expires, subject).into_bytes()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let digit = |c: u8| char::from(c).to_digit(16).map(|digit| digit as u8);
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some(digit(*high)? << 4 | digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The request comes from this machine, i.e. the browser started with the server.
+pub(crate) struct Local;
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Local {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if is_local(request) {
+            Outcome::Success(Local)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// The request comes from this machine or a paired device.
+pub(crate) struct Paired;
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Paired {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if is_paired(request) {
+            Outcome::Success(Paired)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// The request comes from a paired device or carries a media token signed
+/// for the first parameter of the route, i.e. the media ID.
+pub(crate) struct MediaAccess;
+
+#[async_trait]
+impl<'r> FromRequest<'r> for MediaAccess {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if is_paired(request) {
+            return Outcome::Success(MediaAccess);
+        }
+
+        let subject = first_param(request);
+        let token = request.query_value::<&str>("token").and_then(Result::ok);
+        let auth = request.rocket().state::<Auth>();
+
+        match (subject, token, auth) {
+            (Some(subject), Some(token), Some(auth))
+                if auth.verify(subject, token, SystemTime::now()) =>
+            {
+                Outcome::Success(MediaAccess)
+            }
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// The first dynamic segment of the route, e.g. `<id>` in `/video/<id>`.
+fn first_param<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    let route = request.route()?;
+    let segments = |path: &'r str| path.split('/').filter(|segment| !segment.is_empty());
+    let base = segments(route.uri.base()).count();
+    let index = segments(route.uri.path()).position(|segment| segment.starts_with('<'))?;
+
+    // parameters are counted from the mount point
+    request.param::<&str>(index.checked_sub(base)?)?.ok()
+}
+
+/// Only the socket address counts, since `X-Real-IP` is set by the client.
+fn is_local(request: &Request<'_>) -> bool {
+    matches!(request.remote(), Some(remote) if remote.ip().is_loopback())
+}
+
+fn is_paired(request: &Request<'_>) -> bool {
+    if is_local(request) {
+        return true;
+    }
+
+    let auth = match request.rocket().state::<Auth>() {
+        Some(auth) => auth,
+        None => return false,
+    };
+
+    let bearer = request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|header| header.strip_prefix("Bearer "));
+
+    let cookie = request.cookies().get(SESSION_COOKIE).map(|c| c.value());
+
+    [bearer, cookie]
+        .into_iter()
+        .flatten()
+        .any(|token| auth.is_session(token, Instant::now()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{
+        config::LogLevel,
+        http::Header,
+        local::asynchronous::{Client, LocalRequest},
+        routes, Config,
+    };
+    use std::net::SocketAddr;
+    use test_case::test_case;
+
+    async fn client() -> Client {
+        let config = Config {
+            log_level: LogLevel::Off,
+            ..Config::debug_default()
+        };
+
+        let rocket = rocket::custom(config)
+            .mount("/", routes![pin])
+            .manage(Auth::new());
+
+        Client::untracked(rocket).await.expect("rocket")
+    }
+
+    fn get_from<'c>(client: &'c Client, uri: &str, remote: &str) -> LocalRequest<'c> {
+        let remote: SocketAddr = remote.parse().unwrap();
+        client.get(uri.to_owned()).remote(remote)
+    }
+
+    #[test]
+    fn verifies_media_tokens() {
+        let auth = Auth::new();
+        let now = SystemTime::now();
+        let token = auth.media_token("abc", now);
+
+        assert!(auth.verify("abc", &token, now));
+        assert!(!auth.verify("def", &token, now));
+        assert!(!auth.verify("abc", &token, now + MEDIA_TOKEN_TTL));
+        assert!(!auth.verify("abc", "123.456", now));
+        assert!(!auth.verify("abc", &format!("{}0", token), now));
+        assert!(!Auth::new().verify("abc", &token, now));
+    }
+
+    #[test]
+    fn rejects_extended_tokens() {
+        let auth = Auth::new();
+        let now = SystemTime::now();
+        let token = auth.media_token("abc", now);
+        let (_, tag) = token.split_once('.').unwrap();
+        let extended = format!("{}.{}", unix_secs(now + MEDIA_TOKEN_TTL * 2), tag);
+
+        assert!(!auth.verify("abc", &extended, now));
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 168, 1, last])
+    }
+
+    #[test]
+    fn pairs_once_with_the_pin() {
+        let auth = Auth::new();
+        let pin = auth.pin();
+        assert_eq!(pin.len(), PIN_LEN);
+
+        assert!(pin.chars().all(|c| c.is_ascii_digit()));
+
+        let now = Instant::now();
+        let token = auth.pair(&pin, ip(1), now).unwrap();
+        assert!(auth.is_session(&token, now));
+        assert_ne!(auth.pin(), pin);
+    }
+
+    #[test]
+    fn expires_sessions() {
+        let auth = Auth::new();
+        let now = Instant::now();
+        let token = auth.pair(&auth.pin(), ip(1), now).unwrap();
+
+        assert!(auth.is_session(&token, now + SESSION_TTL / 2));
+        assert!(!auth.is_session(&token, now + SESSION_TTL));
+        assert!(!auth.is_session("unknown", now));
+    }
+
+    #[test]
+    fn keeps_pin_after_wrong_guesses() {
+        let auth = Auth::new();
+        let pin = auth.pin();
+        let now = Instant::now();
+
+        // many devices guessing can't keep the owner from pairing
+        for last in 0..100 {
+            assert_eq!(auth.pair("wrong", ip(last), now), Err(PairError::WrongPin));
+        }
+
+        assert_eq!(auth.pin(), pin);
+        assert!(auth.pair(&pin, ip(200), now).is_ok());
+    }
+
+    #[test]
+    fn locks_out_devices_after_wrong_guesses() {
+        let auth = Auth::new();
+        let now = Instant::now();
+
+        assert_eq!(auth.pair("wrong", ip(1), now), Err(PairError::WrongPin));
+        assert_eq!(
+            auth.pair(&auth.pin(), ip(1), now),
+            Err(PairError::LockedOut(LOCKOUT))
+        );
+
+        let later = now + LOCKOUT;
+        assert_eq!(auth.pair("wrong", ip(1), later), Err(PairError::WrongPin));
+        assert_eq!(
+            auth.pair("wrong", ip(1), later + LOCKOUT),
+            Err(PairError::LockedOut(LOCKOUT))
+        );
+
+        // other devices can still pair
+        assert!(auth.pair(&auth.pin(), ip(2), later).is_ok());
+        assert!(auth.pair(&auth.pin(), ip(1), later + LOCKOUT * 2).is_ok());
+    }
+
+    #[test_case(1 => LOCKOUT; "after one wrong guess")]
+    #[test_case(4 => LOCKOUT * 8; "after a few wrong guesses")]
+    #[test_case(100 => MAX_LOCKOUT; "after many wrong guesses")]
+    fn doubles_lockouts(failures: u32) -> Duration {
+        let now = Instant::now();
+        let mut lockout = Lockout {
+            failures: 0,
+            until: now,
+        };
+
+        for _ in 0..failures {
+            lockout.fail(now);
+        }
+
+        lockout.until - now
+    }
+
+    #[tokio::test]
+    async fn shows_the_pin_only_on_this_machine() {
+        let client = client().await;
+
+        let response = get_from(&client, "/auth/pin", "127.0.0.1:8000")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = get_from(&client, "/auth/pin", "192.168.1.20:8000")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[tokio::test]
+    async fn ignores_spoofed_real_ip() {
+        let client = client().await;
+
+        let response = get_from(&client, "/auth/pin", "192.168.1.20:8000")
+            .header(Header::new("X-Real-IP", "127.0.0.1"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+}
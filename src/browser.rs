@@ -0,0 +1,175 @@
+//! The UI is opened in a Chromium-family browser, since casting only works
+//! in those. The browser from the settings is used if there is one,
+//! otherwise the first one found of the usual suspects.
+use crate::settings::Settings;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+use tokio::process::Command;
+
+/// Browser executables looked for on the PATH, most preferred first.
+#[cfg(all(not(target_os = "windows"), not(target_os = "macos")))]
+const BROWSERS: [&str; 8] = [
+    "google-chrome",
+    "google-chrome-stable",
+    "chromium",
+    "chromium-browser",
+    "brave-browser",
+    "brave",
+    "microsoft-edge",
+    "microsoft-edge-stable",
+];
+
+#[cfg(all(not(target_os = "windows"), not(target_os = "macos")))]
+fn candidates() -> Vec<PathBuf> {
+    let paths = std::env::var_os("PATH").unwrap_or_default();
+    let dirs = std::env::split_paths(&paths).collect::<Vec<_>>();
+    search(&dirs, &BROWSERS)
+}
+
+#[cfg(target_os = "macos")]
+fn candidates() -> Vec<PathBuf> {
+    [
+        "Google Chrome.app/Contents/MacOS/Google Chrome",
+        "Chromium.app/Contents/MacOS/Chromium",
+        "Brave Browser.app/Contents/MacOS/Brave Browser",
+        "Microsoft Edge.app/Contents/MacOS/Microsoft Edge",
+    ]
+    .iter()
+    .map(|app| Path::new("/Applications").join(app))
+    .collect()
+}
+
+#[cfg(target_os = "windows")]
+fn candidates() -> Vec<PathBuf> {
+    let dirs = ["ProgramFiles", "ProgramFiles(x86)", "LocalAppData"]
+        .iter()
+        .filter_map(std::env::var_os)
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+
+    search(
+        &dirs,
+        &[
+            r"Google\Chrome\Application\chrome.exe",
+            r"Chromium\Application\chrome.exe",
+            r"BraveSoftware\Brave-Browser\Application\brave.exe",
+            r"Microsoft\Edge\Application\msedge.exe",
+        ],
+    )
+}
+
+/// Every browser in every directory, ordered by browser.
+#[cfg(not(target_os = "macos"))]
+fn search(dirs: &[PathBuf], browsers: &[&str]) -> Vec<PathBuf> {
+    browsers
+        .iter()
+        .flat_map(|browser| dirs.iter().map(move |dir| dir.join(browser)))
+        .collect()
+}
+
+/// The browser from the settings or the first one installed.
+fn find(settings: &Settings) -> Result<PathBuf> {
+    if let Some(browser) = &settings.browser {
+        return Ok(browser.clone());
+    }
+
+    let candidates = candidates();
+    debug!("looking for a browser in {:#?}", candidates);
+
+    candidates
+        .into_iter()
+        .find(|candidate| candidate.is_file())
+        .ok_or_else(|| {
+            anyhow!("no Chromium-family browser was found, set `browser` in the config file")
+        })
+}
+
+/// Opens `url` in the browser and returns when it is closed. The browser
+/// keeps its profile in `user_data_dir`, separate from the user's own.
+pub(crate) async fn run(
+    url: &str,
+    user_data_dir: Option<&Path>,
+    settings: &Settings,
+) -> Result<()> {
+    let browser = find(settings)?;
+
+    let mut args = vec![OsString::from(url)];
+
+    if let Some(dir) = user_data_dir {
+        let mut arg = OsString::from("--user-data-dir=");
+        arg.push(dir);
+        args.push(arg);
+    }
+
+    args.push("--no-default-browser-check".into());
+    args.extend(settings.browser_args.iter().map(OsString::from));
+
+    info!("opening {} with {:?}", browser.display(), args);
+    wait(create_command(&browser).args(&args))
+        .await
+        .with_context(|| format!("failed to open {}", browser.display()))
+}
+
+#[cfg(target_os = "windows")]
+fn create_command(browser: &Path) -> Command {
+    const DETACHED_PROCESS: u32 = 0x00000008;
+    let mut command = Command::new(browser);
+    command.creation_flags(DETACHED_PROCESS);
+    command
+}
+
+#[cfg(not(target_os = "windows"))]
+fn create_command(browser: &Path) -> Command {
+    Command::new(browser)
+}
+
+/// Chromium on Windows returns immediately after launch, so we can't tell
+/// when it is closed. The server is stopped with the shutdown route instead.
+#[cfg(target_os = "windows")]
+async fn wait(command: &mut Command) -> Result<()> {
+    command.spawn()?;
+    futures::future::pending().await
+}
+
+/// Chromium on not-Windows does not return until the last window is closed.
+#[cfg(not(target_os = "windows"))]
+async fn wait(command: &mut Command) -> Result<()> {
+    let exit = command.status().await?;
+    info!("browser stopped with code {}", exit);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_the_configured_browser() {
+        let settings = Settings {
+            browser: Some(PathBuf::from("my-browser")),
+            ..Settings::default()
+        };
+
+        assert_eq!(find(&settings).unwrap(), PathBuf::from("my-browser"));
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn orders_candidates_by_browser() {
+        let dirs = [PathBuf::from("/usr/bin"), PathBuf::from("/snap/bin")];
+
+        assert_eq!(
+            search(&dirs, &["google-chrome", "chromium"]),
+            vec![
+                PathBuf::from("/usr/bin/google-chrome"),
+                PathBuf::from("/snap/bin/google-chrome"),
+                PathBuf::from("/usr/bin/chromium"),
+                PathBuf::from("/snap/bin/chromium"),
+            ]
+        );
+    }
+}
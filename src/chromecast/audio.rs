@@ -0,0 +1,116 @@
+//! Selects which audio track is sent to the Chromecast for files with
+//! more than one, e.g. anime and foreign films. The Chromecast only
+//! plays one track, so the others are left out when remuxing or transcoding.
+use crate::probe::AudioStream;
+use log::debug;
+use rocket::FromFormField;
+use serde::Deserialize;
+
+/// What audio is encoded to when it must be transcoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub(crate) enum AudioCodec {
+    /// AAC stereo, supported by all Chromecasts.
+    #[default]
+    #[field(value = "aac")]
+    Aac,
+
+    /// AC3 up to 5.1, passed through to e.g. a soundbar.
+    #[field(value = "ac3")]
+    Ac3,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+pub(crate) struct AudioConfig {
+    /// Preferred audio languages as ISO 639-2 codes, e.g. "jpn", most preferred first.
+    #[serde(default)]
+    pub(crate) audio_languages: Vec<String>,
+}
+
+/// Selects the audio track with the stream `index` if given. Otherwise the
+/// first track in the `lang` language or one of the preferred languages is
+/// selected, falling back to the default track and then the first track.
+pub(super) fn select<'a>(
+    streams: &'a [AudioStream],
+    index: Option<u32>,
+    lang: Option<&str>,
+    config: &AudioConfig,
+) -> Option<&'a AudioStream> {
+    if let Some(index) = index {
+        if let Some(stream) = streams.iter().find(|stream| stream.index == index) {
+            return Some(stream);
+        }
+
+        debug!("audio stream {} not found, selecting by language", index);
+    }
+
+    let languages = lang
+        .into_iter()
+        .chain(config.audio_languages.iter().map(String::as_str));
+
+    for language in languages {
+        let stream = streams
+            .iter()
+            .find(|stream| matches!(&stream.language, Some(l) if l.eq_ignore_ascii_case(language)));
+
+        if stream.is_some() {
+            return stream;
+        }
+    }
+
+    streams
+        .iter()
+        .find(|stream| stream.default)
+        .or_else(|| streams.first())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn stream(index: u32, language: &str, default: bool) -> AudioStream {
+        AudioStream {
+            index,
+            codec: Some("aac".to_owned()),
+            language: Some(language.to_owned()),
+            title: None,
+            channels: Some(2),
+            channel_layout: Some("stereo".to_owned()),
+            bit_rate: None,
+            default,
+        }
+    }
+
+    #[test_case(None, None, &[] => Some(2); "when nothing is preferred it selects default track")]
+    #[test_case(Some(3), None, &["jpn"] => Some(3); "when index is given")]
+    #[test_case(Some(9), None, &[] => Some(2); "when index does not exist")]
+    #[test_case(None, Some("JPN"), &["eng"] => Some(1); "when lang is given")]
+    #[test_case(None, Some("ger"), &["fre", "eng"] => Some(2); "when lang does not exist it uses preferences")]
+    #[test_case(None, None, &["ger"] => Some(2); "when no preference exists")]
+    fn works(index: Option<u32>, lang: Option<&str>, languages: &[&str]) -> Option<u32> {
+        let streams = [
+            stream(1, "jpn", false),
+            stream(2, "eng", true),
+            stream(3, "eng", false),
+        ];
+
+        let config = AudioConfig {
+            audio_languages: languages.iter().map(|l| l.to_string()).collect(),
+        };
+
+        select(&streams, index, lang, &config).map(|stream| stream.index)
+    }
+
+    #[test]
+    fn selects_first_track_without_default() {
+        let streams = [stream(1, "jpn", false), stream(2, "eng", false)];
+        let config = AudioConfig::default();
+        let selected = select(&streams, None, None, &config);
+        assert_eq!(selected.map(|stream| stream.index), Some(1));
+    }
+
+    #[test]
+    fn selects_nothing_without_tracks() {
+        assert!(select(&[], Some(1), Some("eng"), &AudioConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,132 @@
+//! Burns subtitles into the video when transcoding. The Chromecast only
+//! renders WebVTT, so image-based tracks (PGS, VobSub) and styled ASS
+//! tracks must be drawn onto the video frames by ffmpeg instead.
+use crate::probe::SubtitleStream;
+use log::debug;
+use std::path::Path;
+
+/// How the selected subtitle track is drawn onto the video.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum BurnIn {
+    /// Overlay the bitmaps of the image-based track with stream `index`.
+    Image { index: u32 },
+
+    /// Render the text track with libass. `position` counts subtitle
+    /// tracks only, as expected by the `subtitles` filter.
+    Text { position: usize },
+}
+
+/// Selects the subtitle track with the stream `index`.
+pub(super) fn select(streams: &[SubtitleStream], index: u32) -> Option<BurnIn> {
+    let position = streams.iter().position(|stream| stream.index == index);
+
+    match position {
+        Some(position) if streams[position].image_based => Some(BurnIn::Image { index }),
+        Some(position) => Some(BurnIn::Text { position }),
+        None => {
+            debug!("subtitle stream {} not found", index);
+            None
+        }
+    }
+}
+
+/// Returns the filters burning the subtitles of `path` into the video,
+/// see `transcode::filter_graph`.
+pub(super) fn filter(path: &Path, burn_in: BurnIn, start: Option<f64>) -> String {
+    match burn_in {
+        BurnIn::Image { index } => format!("[0:{}]overlay", index),
+        BurnIn::Text { position } => {
+            let subtitles = format!(
+                "subtitles=filename={}:si={}",
+                escape(&path.to_string_lossy()),
+                position
+            );
+
+            match start {
+                // the filter reads the subtitles from the start of the file, so
+                // shift the seeked video to the original timestamps and back
+                Some(start) => format!(
+                    "setpts=PTS+{:.3}/TB,{},setpts=PTS-STARTPTS",
+                    start, subtitles
+                ),
+                None => subtitles,
+            }
+        }
+    }
+}
+
+/// Escapes a filter option value and then the filter graph description,
+/// see https://ffmpeg.org/ffmpeg-filters.html#Notes-on-filtergraph-escaping
+fn escape(value: &str) -> String {
+    let mut option = String::new();
+
+    for c in value.chars() {
+        if matches!(c, '\\' | ':' | '\'') {
+            option.push('\\');
+        }
+
+        option.push(c);
+    }
+
+    let mut graph = String::new();
+
+    for c in option.chars() {
+        if matches!(c, '\\' | '\'' | '[' | ']' | ',' | ';') {
+            graph.push('\\');
+        }
+
+        graph.push(c);
+    }
+
+    graph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn stream(index: u32, codec: &str, image_based: bool) -> SubtitleStream {
+        SubtitleStream {
+            index,
+            codec: Some(codec.to_owned()),
+            language: None,
+            title: None,
+            default: false,
+            forced: false,
+            image_based,
+        }
+    }
+
+    #[test_case(3 => Some(BurnIn::Image { index: 3 }); "when track is image based")]
+    #[test_case(4 => Some(BurnIn::Text { position: 1 }); "when track is text")]
+    #[test_case(9 => None; "when track does not exist")]
+    fn selects(index: u32) -> Option<BurnIn> {
+        let streams = [
+            stream(3, "hdmv_pgs_subtitle", true),
+            stream(4, "ass", false),
+        ];
+
+        select(&streams, index)
+    }
+
+    #[test_case("/videos/movie.mkv" => "/videos/movie.mkv"; "when path is plain")]
+    #[test_case(r"C:\Videos\movie.mkv" => r"C\\:\\\\Videos\\\\movie.mkv"; "when path is windows")]
+    #[test_case("/videos/it's [1080p], ok.mkv" => r"/videos/it\\\'s \[1080p\]\, ok.mkv"; "when path has special characters")]
+    fn escapes(path: &str) -> String {
+        escape(path)
+    }
+
+    #[test]
+    fn overlays_image_track() {
+        let filter = filter(Path::new("a.mkv"), BurnIn::Image { index: 3 }, Some(60.0));
+        assert_eq!(filter, "[0:3]overlay");
+    }
+
+    #[test]
+    fn shifts_text_track_when_seeking() {
+        let filter = filter(Path::new("a.mkv"), BurnIn::Text { position: 1 }, Some(60.0));
+        let expected = "setpts=PTS+60.000/TB,subtitles=filename=a.mkv:si=1,setpts=PTS-STARTPTS";
+        assert_eq!(filter, expected);
+    }
+}
@@ -0,0 +1,127 @@
+//! Validators and conditional request evaluation as per RFC 7232,
+//! plus the If-Range precondition from RFC 7233.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// ETag and Last-Modified validators for a file.
+#[derive(Debug)]
+pub(crate) struct Validators {
+    etag: String,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Creates validators from the size and modification time of a file.
+    /// The ETag is stable for as long as neither changes.
+    pub(crate) fn new(size: u64, modified: Option<SystemTime>) -> Self {
+        let since_epoch = modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        let nanos = since_epoch.unwrap_or_default().as_nanos();
+
+        Self {
+            etag: format!("\"{:x}-{:x}\"", nanos, size),
+            // HTTP dates only have second precision
+            last_modified: since_epoch.map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs())),
+        }
+    }
+
+    pub(crate) fn etag(&self) -> &str {
+        &self.etag
+    }
+
+    pub(crate) fn last_modified(&self) -> Option<String> {
+        self.last_modified.map(httpdate::fmt_http_date)
+    }
+
+    /// Returns true if the client's copy is current and a 304 Not Modified
+    /// should be sent instead of the file. If-Modified-Since is ignored when
+    /// If-None-Match is present.
+    pub(crate) fn not_modified(
+        &self,
+        if_none_match: Option<&str>,
+        if_modified_since: Option<&str>,
+    ) -> bool {
+        if let Some(if_none_match) = if_none_match {
+            return if_none_match.trim() == "*"
+                || if_none_match
+                    .split(',')
+                    .any(|tag| weak_eq(tag.trim(), &self.etag));
+        }
+
+        match (if_modified_since.and_then(parse_date), self.last_modified) {
+            (Some(since), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the Range header should be honoured. If the If-Range
+    /// validator is stale, the entire file must be sent instead.
+    pub(crate) fn range_applies(&self, if_range: Option<&str>) -> bool {
+        let if_range = match if_range {
+            Some(if_range) => if_range.trim(),
+            None => return true,
+        };
+
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            // If-Range requires the strong comparison function
+            if_range == self.etag
+        } else {
+            match (parse_date(if_range), self.last_modified) {
+                (Some(date), Some(last_modified)) => date == last_modified,
+                _ => false,
+            }
+        }
+    }
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn parse_date(s: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(s.trim()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+    const EARLIER: &str = "Sun, 06 Nov 1994 08:49:36 GMT";
+    const LATER: &str = "Sun, 06 Nov 1994 08:49:38 GMT";
+
+    fn validators() -> Validators {
+        let modified = UNIX_EPOCH + Duration::new(784_111_777, 500);
+        Validators::new(1000, Some(modified))
+    }
+
+    #[test]
+    fn formats_validators() {
+        let validators = validators();
+        assert_eq!(validators.etag(), "\"ae1b981bc490bf4-3e8\"");
+        assert_eq!(validators.last_modified().as_deref(), Some(DATE));
+    }
+
+    #[test_case(None, None => false; "when no preconditions")]
+    #[test_case(Some("*"), None => true; "when if-none-match is any")]
+    #[test_case(Some("\"ae1b981bc490bf4-3e8\""), None => true; "when etag matches")]
+    #[test_case(Some("W/\"ae1b981bc490bf4-3e8\""), None => true; "when weak etag matches")]
+    #[test_case(Some("\"a\", \"ae1b981bc490bf4-3e8\""), None => true; "when etag in list matches")]
+    #[test_case(Some("\"a\""), Some(DATE) => false; "when etag differs and date matches")]
+    #[test_case(None, Some(DATE) => true; "when not modified since")]
+    #[test_case(None, Some(LATER) => true; "when not modified since later date")]
+    #[test_case(None, Some(EARLIER) => false; "when modified since")]
+    #[test_case(None, Some("garbage") => false; "when date is invalid")]
+    fn not_modified(if_none_match: Option<&str>, if_modified_since: Option<&str>) -> bool {
+        validators().not_modified(if_none_match, if_modified_since)
+    }
+
+    #[test_case(None => true; "when no if-range")]
+    #[test_case(Some("\"ae1b981bc490bf4-3e8\"") => true; "when etag matches")]
+    #[test_case(Some("W/\"ae1b981bc490bf4-3e8\"") => false; "when etag is weak")]
+    #[test_case(Some("\"a\"") => false; "when etag differs")]
+    #[test_case(Some(DATE) => true; "when date matches")]
+    #[test_case(Some(LATER) => false; "when date differs")]
+    fn range_applies(if_range: Option<&str>) -> bool {
+        validators().range_applies(if_range)
+    }
+}
@@ -0,0 +1,559 @@
+//! HLS output for transcoded videos. Unlike a single piped transcode,
+//! HLS lets the receiver seek anywhere in the video because each segment
+//! is transcoded on demand and the playlist covers the full duration.
+use super::{
+    audio::AudioConfig,
+    transcode::{self, Options, StreamQuery},
+};
+use crate::{
+    app_result::AppResult,
+    auth::MediaAccess,
+    ffmpeg,
+    library::Library,
+    media::MediaRegistry,
+    probe::{self, MediaInfo},
+    profile::ProfileConfig,
+};
+use anyhow::{anyhow, Context, Error};
+use log::{debug, error, info};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rocket::{
+    get,
+    http::{ContentType, Status},
+    response::content::Custom,
+    State,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    env,
+    ffi::OsString,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{fs, fs::File, sync::Mutex as AsyncMutex};
+
+/// Length of each segment in seconds.
+const SEGMENT_SECS: f64 = 6.0;
+
+/// Segments of videos that have not been requested for this long are deleted.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The audio track is selected here and passed on to the other playlists
+/// and segments as the `audio` stream index. The receiver profile and
+/// subtitles to burn in are passed on too, so segments are encoded for it.
+#[get("/hls/<id>/master.m3u8?<token>&<query..>")]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn master(
+    _access: MediaAccess,
+    id: &str,
+    token: Option<&str>,
+    query: StreamQuery<'_>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    cache: &State<HlsCache>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> Result<Option<Custom<String>>, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(None),
+    };
+
+    debug!("hls master playlist for {}", path.display());
+    let info = cache.info(&path).await?;
+    let (_, options) = transcode::options(&info, &query, config, profiles);
+    let query = playlist_query(&query, options.audio, token);
+    Ok(Some(Custom(
+        playlist_content_type(),
+        master_playlist(&query),
+    )))
+}
+
+#[get("/hls/<id>/index.m3u8?<token>&<query..>")]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn index(
+    _access: MediaAccess,
+    id: &str,
+    token: Option<&str>,
+    query: StreamQuery<'_>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    cache: &State<HlsCache>,
+) -> Result<Option<Custom<String>>, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(None),
+    };
+
+    let duration = duration(&path, &*cache.info(&path).await?)?;
+    info!("hls playlist for {} ({} s)", path.display(), duration);
+    Ok(Some(Custom(
+        playlist_content_type(),
+        media_playlist(duration, &playlist_query(&query, query.audio, token)),
+    )))
+}
+
+/// Segments are encoded with the same options as `transcode::prepare`
+/// would use for the query.
+#[get("/hls/<id>/<segment>?<query..>", rank = 2)]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn segment(
+    _access: MediaAccess,
+    id: &str,
+    segment: &str,
+    query: StreamQuery<'_>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    cache: &State<HlsCache>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> Result<Option<(ContentType, File)>, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(None),
+    };
+
+    let index = match segment
+        .strip_suffix(".ts")
+        .and_then(|index| index.parse::<u64>().ok())
+    {
+        Some(index) => index,
+        None => {
+            let err = format!("invalid segment: {}", segment);
+            return Err(AppResult::error(err).with_status(Status::BadRequest));
+        }
+    };
+
+    let info = cache.info(&path).await?;
+    let duration = duration(&path, &info)?;
+    let count = segment_count(duration);
+
+    if index >= count {
+        let err = format!("segment {} is out of range", index);
+        return Err(AppResult::error(err).with_status(Status::NotFound));
+    }
+
+    let (_, options) = transcode::options(&info, &query, config, profiles);
+    let file = cache.segment(&path, index, duration, &options).await?;
+
+    if index + 1 < count {
+        // start working on the next segment while this one is being played
+        let cache = cache.inner().clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = cache.segment(&path, index + 1, duration, &options).await {
+                error!("failed to prefetch segment {}: {}", index + 1, err);
+            }
+        });
+    }
+
+    let file = File::open(&file)
+        .await
+        .with_context(|| format!("failed to open segment: {:#?}", file))?;
+
+    Ok(Some((ContentType::new("video", "mp2t"), file)))
+}
+
+/// Transcoded segments in a temporary directory, one directory per video.
+#[derive(Clone)]
+pub(crate) struct HlsCache {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    root: PathBuf,
+    // segment paths being written, so each segment is only transcoded once
+    locks: Mutex<HashMap<PathBuf, Arc<AsyncMutex<()>>>>,
+    last_access: Mutex<HashMap<PathBuf, Instant>>,
+    // every playlist and segment request needs the probe, which takes a while
+    probes: Mutex<HashMap<PathBuf, Probe>>,
+}
+
+/// The probe of a file, along with what identifies the version probed.
+struct Probe {
+    len: u64,
+    modified: Option<SystemTime>,
+    info: Arc<MediaInfo>,
+    last_access: Instant,
+}
+
+impl HlsCache {
+    pub(crate) fn new() -> Self {
+        let mut root = env::temp_dir();
+        root.push("videocaster-hls");
+
+        Self {
+            inner: Arc::new(Inner {
+                root,
+                locks: Mutex::default(),
+                last_access: Mutex::default(),
+                probes: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Probes the file at `path`, unless it was probed before and has not
+    /// changed since.
+    async fn info(&self, path: &Path) -> Result<Arc<MediaInfo>, Error> {
+        let metadata = fs::metadata(path)
+            .await
+            .with_context(|| format!("failed to load metadata for file: {:#?}", path))?;
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+
+        if let Some(probe) = self.inner.probes.lock().expect("hls probes").get_mut(path) {
+            if probe.len == len && probe.modified == modified {
+                probe.last_access = Instant::now();
+                return Ok(probe.info.clone());
+            }
+        }
+
+        let info = Arc::new(probe::probe(path).await?);
+        self.inner.probes.lock().expect("hls probes").insert(
+            path.to_path_buf(),
+            Probe {
+                len,
+                modified,
+                info: info.clone(),
+                last_access: Instant::now(),
+            },
+        );
+        Ok(info)
+    }
+
+    /// Returns the path of segment `index` of the video at `path`,
+    /// transcoding it first with the `options` if it is not in the cache.
+    async fn segment(
+        &self,
+        path: &Path,
+        index: u64,
+        duration: f64,
+        options: &Options,
+    ) -> Result<PathBuf, Error> {
+        let dir = self.dir(path, options).await?;
+        let file = dir.join(format!("{}.ts", index));
+        self.touch(&dir);
+
+        let lock = {
+            let mut locks = self.inner.locks.lock().expect("hls locks");
+            locks.entry(file.clone()).or_default().clone()
+        };
+
+        let _guard = lock.lock().await;
+
+        if fs::metadata(&file).await.is_ok() {
+            debug!("segment {} is cached at {}", index, file.display());
+            return Ok(file);
+        }
+
+        let start = index as f64 * SEGMENT_SECS;
+        let length = SEGMENT_SECS.min(duration - start);
+        let part = dir.join(format!("{}.part", index));
+        info!("transcoding segment {} of {}", index, path.display());
+        let options = Options {
+            start: Some(start).filter(|start| *start > 0.0),
+            ..*options
+        };
+
+        ffmpeg::run(&segment_args(path, &options, length, &part)).await?;
+        fs::rename(&part, &file).await?;
+        Ok(file)
+    }
+
+    async fn dir(&self, path: &Path, options: &Options) -> Result<PathBuf, Error> {
+        let metadata = fs::metadata(path)
+            .await
+            .with_context(|| format!("failed to load metadata for file: {:#?}", path))?;
+
+        // a new directory is used if the file or the options change
+        let options = Options {
+            start: None,
+            ..*options
+        };
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        format!("{:?}", options).hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        metadata.modified().ok().hash(&mut hasher);
+
+        let dir = self.inner.root.join(format!("{:016x}", hasher.finish()));
+        fs::create_dir_all(&dir).await?;
+        Ok(dir)
+    }
+
+    fn touch(&self, dir: &Path) {
+        let mut last_access = self.inner.last_access.lock().expect("hls last access");
+        last_access.insert(dir.to_path_buf(), Instant::now());
+    }
+
+    /// Deletes segments left over from earlier runs and then periodically
+    /// deletes segments and probes of videos that are no longer being played.
+    pub(crate) async fn clean_up(self) {
+        if fs::metadata(&self.inner.root).await.is_ok() {
+            info!("deleting old hls segments in {}", self.inner.root.display());
+
+            if let Err(err) = fs::remove_dir_all(&self.inner.root).await {
+                error!("failed to delete old hls segments: {}", err);
+            }
+        }
+
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            let idle = {
+                let mut last_access = self.inner.last_access.lock().expect("hls last access");
+                let idle: Vec<PathBuf> = last_access
+                    .iter()
+                    .filter(|(_, accessed)| accessed.elapsed() > IDLE_TIMEOUT)
+                    .map(|(dir, _)| dir.clone())
+                    .collect();
+
+                for dir in &idle {
+                    last_access.remove(dir);
+                }
+
+                idle
+            };
+
+            self.inner
+                .probes
+                .lock()
+                .expect("hls probes")
+                .retain(|_, probe| probe.last_access.elapsed() <= IDLE_TIMEOUT);
+
+            for dir in idle {
+                info!("deleting idle hls segments in {}", dir.display());
+
+                self.inner
+                    .locks
+                    .lock()
+                    .expect("hls locks")
+                    .retain(|file, _| !file.starts_with(&dir));
+
+                if let Err(err) = fs::remove_dir_all(&dir).await {
+                    error!("failed to delete {}: {}", dir.display(), err);
+                }
+            }
+        }
+    }
+}
+
+fn duration(path: &Path, info: &MediaInfo) -> Result<f64, Error> {
+    info.duration
+        .ok_or_else(|| anyhow!("duration of {} is unknown", path.display()))
+}
+
+fn segment_args(path: &Path, options: &Options, length: f64, output: &Path) -> Vec<OsString> {
+    let start = options.start.unwrap_or_default();
+    let mut args = transcode::encode_args(path, options);
+    args.push("-t".into());
+    args.push(format!("{:.3}", length).into());
+    // keep timestamps continuous across segments
+    args.push("-output_ts_offset".into());
+    args.push(format!("{:.3}", start).into());
+    args.push("-f".into());
+    args.push("mpegts".into());
+    args.push("-y".into());
+    args.push(output.into());
+    args
+}
+
+fn segment_count(duration: f64) -> u64 {
+    (duration / SEGMENT_SECS).ceil().max(0.0) as u64
+}
+
+fn playlist_content_type() -> ContentType {
+    ContentType::new("application", "vnd.apple.mpegurl")
+}
+
+fn master_playlist(query: &str) -> String {
+    // H.264 high profile level 4.1 and AAC-LC, see transcode::encode_args
+    [
+        "#EXTM3U",
+        "#EXT-X-VERSION:3",
+        "#EXT-X-STREAM-INF:BANDWIDTH=8000000,CODECS=\"avc1.640029,mp4a.40.2\"",
+        &format!("index.m3u8{}", query),
+        "",
+    ]
+    .join("\n")
+}
+
+fn media_playlist(duration: f64, query: &str) -> String {
+    let mut playlist = String::new();
+    playlist.push_str("#EXTM3U\n");
+    playlist.push_str("#EXT-X-VERSION:3\n");
+    playlist.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+    playlist.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", SEGMENT_SECS));
+    playlist.push_str("#EXT-X-MEDIA-SEQUENCE:0\n");
+
+    for index in 0..segment_count(duration) {
+        let start = index as f64 * SEGMENT_SECS;
+        let length = SEGMENT_SECS.min(duration - start);
+        playlist.push_str(&format!("#EXTINF:{:.3},\n{}.ts{}\n", length, index, query));
+    }
+
+    playlist.push_str("#EXT-X-ENDLIST\n");
+    playlist
+}
+
+/// The query of the URLs in playlists, which pass on the `audio` track,
+/// what the segments are encoded for and the media token, since receivers
+/// don't add it by themselves.
+fn playlist_query(query: &StreamQuery<'_>, audio: Option<u32>, token: Option<&str>) -> String {
+    let encode = |value: &str| utf8_percent_encode(value, NON_ALPHANUMERIC).to_string();
+
+    let params: Vec<String> = [
+        audio.map(|audio| format!("audio={}", audio)),
+        query
+            .subtitle
+            .map(|subtitle| format!("subtitle={}", subtitle)),
+        query
+            .profile
+            .map(|profile| format!("profile={}", encode(profile))),
+        query
+            .device
+            .map(|device| format!("device={}", encode(device))),
+        token.map(|token| format!("token={}", encode(token))),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    if params.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", params.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Auth, probe::Verdict};
+    use rocket::{config::LogLevel, local::asynchronous::Client, routes, Config};
+    use std::net::SocketAddr;
+
+    /// A client for a cache that knows the video at `path` is 10 s long,
+    /// so ffprobe isn't needed.
+    async fn client(path: &Path) -> Client {
+        let config = Config {
+            log_level: LogLevel::Off,
+            ..Config::debug_default()
+        };
+
+        let cache = HlsCache::new();
+        let metadata = fs::metadata(path).await.unwrap();
+        let info = MediaInfo {
+            container: None,
+            duration: Some(10.0),
+            bit_rate: None,
+            video: Vec::new(),
+            audio: Vec::new(),
+            subtitles: Vec::new(),
+            verdict: Verdict::Transcode,
+        };
+
+        cache.inner.probes.lock().unwrap().insert(
+            dunce::canonicalize(path).unwrap(),
+            Probe {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+                info: Arc::new(info),
+                last_access: Instant::now(),
+            },
+        );
+
+        let rocket = rocket::custom(config)
+            .mount("/", routes![segment])
+            .manage(Auth::new())
+            .manage(Library::new(&[path.parent().unwrap().to_path_buf()]))
+            .manage(MediaRegistry::default())
+            .manage(cache)
+            .manage(AudioConfig::default())
+            .manage(ProfileConfig::default());
+
+        Client::untracked(rocket).await.expect("rocket")
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_segments() {
+        let root = env::temp_dir().join("videocaster-hls-videos");
+        fs::create_dir_all(&root).await.unwrap();
+        let path = root.join("video.mkv");
+        fs::write(&path, b"video").await.unwrap();
+
+        let client = client(&path).await;
+        let media = client.rocket().state::<MediaRegistry>().expect("media");
+        let id = media.register(&path);
+        let local: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+
+        for (segment, status) in [
+            ("first.ts", Status::BadRequest),
+            ("1.mp4", Status::BadRequest),
+            ("2.ts", Status::NotFound),
+        ] {
+            let uri = format!("/hls/{}/{}", id, segment);
+            let response = client.get(uri).remote(local).dispatch().await;
+            assert_eq!(response.status(), status, "{}", segment);
+        }
+
+        fs::remove_file(path).await.unwrap();
+    }
+
+    #[test]
+    fn media_playlist_covers_full_duration() {
+        let expected = "#EXTM3U\n\
+            #EXT-X-VERSION:3\n\
+            #EXT-X-PLAYLIST-TYPE:VOD\n\
+            #EXT-X-TARGETDURATION:6\n\
+            #EXT-X-MEDIA-SEQUENCE:0\n\
+            #EXTINF:6.000,\n0.ts\n\
+            #EXTINF:6.000,\n1.ts\n\
+            #EXTINF:1.500,\n2.ts\n\
+            #EXT-X-ENDLIST\n";
+
+        assert_eq!(media_playlist(13.5, ""), expected);
+    }
+
+    #[test]
+    fn media_playlist_is_empty_for_zero_duration() {
+        assert!(!media_playlist(0.0, "").contains("#EXTINF"));
+    }
+
+    #[test]
+    fn playlists_pass_on_audio_track() {
+        let query = playlist_query(&StreamQuery::new(None, None), Some(2), None);
+        assert!(master_playlist(&query).contains("\nindex.m3u8?audio=2\n"));
+        assert!(media_playlist(6.0, &query).contains("\n0.ts?audio=2\n"));
+    }
+
+    #[test]
+    fn playlists_pass_on_token() {
+        let none = StreamQuery::new(None, None);
+        let query = playlist_query(&none, Some(2), Some("123.abc"));
+        assert!(media_playlist(6.0, &query).contains("\n0.ts?audio=2&token=123%2Eabc\n"));
+        assert_eq!(
+            playlist_query(&none, None, Some("123.abc")),
+            "?token=123%2Eabc"
+        );
+    }
+
+    #[test]
+    fn playlists_pass_on_receiver_and_subtitles() {
+        let query = StreamQuery {
+            subtitle: Some(3),
+            ..StreamQuery::new(None, Some("Living Room TV"))
+        };
+
+        assert_eq!(
+            playlist_query(&query, Some(1), None),
+            "?audio=1&subtitle=3&device=Living%20Room%20TV"
+        );
+    }
+}
@@ -0,0 +1,630 @@
+//! Background transcoding of whole files, e.g. before movie night, so
+//! weak machines don't have to transcode while the video is playing.
+//! Finished files are served by the video handler instead of the original.
+use super::{
+    audio::AudioConfig,
+    transcode::{self, Mode, StreamQuery},
+};
+use crate::{
+    app_result::AppResult,
+    auth::Paired,
+    ffmpeg,
+    library::Library,
+    media::MediaRegistry,
+    probe,
+    probe::Verdict,
+    profile::{Profile, ProfileConfig},
+};
+use anyhow::{anyhow, Context, Error};
+use log::{debug, error, info, warn};
+use rocket::{get, post, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::UNIX_EPOCH,
+};
+use tokio::{
+    fs,
+    sync::{Notify, Semaphore},
+};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct JobConfig {
+    /// How many files are transcoded at the same time.
+    #[serde(default = "default_workers")]
+    pub(crate) transcode_workers: usize,
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) struct Job {
+    id: u64,
+
+    /// The media ID of the video.
+    media: String,
+
+    /// The file name of the video.
+    name: String,
+
+    #[serde(skip)]
+    path: PathBuf,
+
+    /// Where the transcoded file is written, which only one job may do.
+    #[serde(skip)]
+    output: PathBuf,
+
+    profile: Option<String>,
+    device: Option<String>,
+    state: JobState,
+
+    /// How much of the file has been transcoded, from 0 to 100.
+    progress: Option<f64>,
+
+    /// Estimated seconds left.
+    eta: Option<f64>,
+
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub(crate) struct NewJob {
+    /// The media ID of the video.
+    media: String,
+
+    /// Name of the capability profile to transcode for.
+    profile: Option<String>,
+
+    /// Name of the receiver to transcode for if `profile` is not given.
+    device: Option<String>,
+}
+
+#[get("/jobs")]
+pub(crate) async fn list(_paired: Paired, jobs: &State<JobQueue>) -> AppResult<Vec<Job>> {
+    AppResult::success(jobs.list())
+}
+
+#[post("/jobs", data = "<job>")]
+pub(crate) async fn enqueue(
+    _paired: Paired,
+    job: Json<NewJob>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    jobs: &State<JobQueue>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> AppResult<Job> {
+    let job = job.into_inner();
+
+    let path = match media.path(&job.media).map(|path| library.check(&path)) {
+        Some(Ok(path)) if path.is_file() => path,
+        Some(Err(err)) => return err.into(),
+        Some(Ok(path)) => return AppResult::error(format!("{} is not a file", path.display())),
+        None => return AppResult::error(format!("unknown media id {}", job.media)),
+    };
+
+    jobs.enqueue(job, path, config, profiles).await.into()
+}
+
+#[post("/jobs/<id>/cancel")]
+pub(crate) async fn cancel(_paired: Paired, id: u64, jobs: &State<JobQueue>) -> AppResult<Job> {
+    jobs.cancel(id).into()
+}
+
+#[post("/jobs/<id>/retry")]
+pub(crate) async fn retry(
+    _paired: Paired,
+    id: u64,
+    jobs: &State<JobQueue>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> AppResult<Job> {
+    jobs.retry(id, config, profiles).into()
+}
+
+/// Transcode jobs run by a limited number of workers.
+#[derive(Clone)]
+pub(crate) struct JobQueue {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    root: PathBuf,
+    workers: Semaphore,
+    next_id: AtomicU64,
+    jobs: Mutex<Vec<Entry>>,
+}
+
+struct Entry {
+    job: Job,
+    cancel: Arc<Notify>,
+}
+
+impl JobQueue {
+    /// Creates a queue writing finished files to `root`.
+    pub(crate) fn new(root: PathBuf, workers: usize) -> Self {
+        info!(
+            "transcoding {} file(s) at a time to {}",
+            workers,
+            root.display()
+        );
+
+        Self {
+            inner: Arc::new(Inner {
+                root,
+                workers: Semaphore::new(workers.max(1)),
+                next_id: AtomicU64::new(1),
+                jobs: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Returns the transcoded file for the video at `path` if there is one
+    /// for receivers with the `profile`.
+    pub(crate) async fn output(&self, path: &Path, profile: &Profile) -> Option<PathBuf> {
+        let output = output_path(&self.inner.root, path, profile).await.ok()?;
+        fs::metadata(&output).await.ok().map(|_| output)
+    }
+
+    fn list(&self) -> Vec<Job> {
+        let jobs = self.inner.jobs.lock().expect("jobs");
+        jobs.iter().map(|entry| entry.job.clone()).collect()
+    }
+
+    /// Queues a job transcoding the video at `path`, unless a job for the
+    /// same output is queued, running or done already, which is returned
+    /// instead.
+    async fn enqueue(
+        &self,
+        new: NewJob,
+        path: PathBuf,
+        config: &AudioConfig,
+        profiles: &ProfileConfig,
+    ) -> Result<Job, Error> {
+        let profile = profiles.resolve(new.profile.as_deref(), new.device.as_deref());
+        let output = output_path(&self.inner.root, &path, &profile).await?;
+        let exists = fs::metadata(&output).await.is_ok();
+        let mut jobs = self.inner.jobs.lock().expect("jobs");
+
+        let existing = jobs.iter().find(|entry| {
+            entry.job.output == output
+                && match entry.job.state {
+                    JobState::Queued | JobState::Running => true,
+                    JobState::Done => exists,
+                    JobState::Failed | JobState::Cancelled => false,
+                }
+        });
+
+        if let Some(entry) = existing {
+            info!("job {} already transcodes {}", entry.job.id, path.display());
+            return Ok(entry.job.clone());
+        }
+
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = Arc::new(Notify::new());
+
+        let name = path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned();
+
+        let job = Job {
+            id,
+            media: new.media,
+            name,
+            path,
+            output,
+            profile: new.profile,
+            device: new.device,
+            state: JobState::Queued,
+            progress: None,
+            eta: None,
+            error: None,
+        };
+
+        info!("queued job {} for {}", id, job.path.display());
+
+        jobs.push(Entry {
+            job: job.clone(),
+            cancel: cancel.clone(),
+        });
+        drop(jobs);
+
+        self.spawn(job.clone(), cancel, config, profiles);
+        Ok(job)
+    }
+
+    fn cancel(&self, id: u64) -> Result<Job, Error> {
+        self.update(id, |entry| match entry.job.state {
+            JobState::Queued | JobState::Running => {
+                info!("cancelling job {}", id);
+                entry.job.state = JobState::Cancelled;
+                entry.job.eta = None;
+                entry.cancel.notify_one();
+                Ok(())
+            }
+            state => Err(anyhow!("job {} is {:?}", id, state)),
+        })
+    }
+
+    /// Queues a failed or cancelled job again, unless another job writes
+    /// the same output in the meantime.
+    fn retry(&self, id: u64, config: &AudioConfig, profiles: &ProfileConfig) -> Result<Job, Error> {
+        let cancel = Arc::new(Notify::new());
+        let mut jobs = self.inner.jobs.lock().expect("jobs");
+
+        let output = jobs
+            .iter()
+            .find(|entry| entry.job.id == id)
+            .map(|entry| entry.job.output.clone())
+            .ok_or_else(|| anyhow!("job {} not found", id))?;
+
+        let active = jobs.iter().find(|entry| {
+            entry.job.output == output
+                && matches!(entry.job.state, JobState::Queued | JobState::Running)
+        });
+
+        if let Some(entry) = active {
+            return Err(anyhow!("job {} already transcodes this file", entry.job.id));
+        }
+
+        let entry = jobs
+            .iter_mut()
+            .find(|entry| entry.job.id == id)
+            .ok_or_else(|| anyhow!("job {} not found", id))?;
+
+        match entry.job.state {
+            JobState::Failed | JobState::Cancelled => {
+                info!("retrying job {}", id);
+                entry.job.state = JobState::Queued;
+                entry.job.progress = None;
+                entry.job.eta = None;
+                entry.job.error = None;
+                entry.cancel = cancel.clone();
+            }
+            state => return Err(anyhow!("job {} is {:?}", id, state)),
+        }
+
+        let job = entry.job.clone();
+        drop(jobs);
+
+        self.spawn(job.clone(), cancel, config, profiles);
+        Ok(job)
+    }
+
+    fn spawn(&self, job: Job, cancel: Arc<Notify>, config: &AudioConfig, profiles: &ProfileConfig) {
+        let queue = self.clone();
+        let config = config.clone();
+        let profiles = profiles.clone();
+        tokio::spawn(async move { queue.work(job, cancel, config, profiles).await });
+    }
+
+    async fn work(
+        self,
+        job: Job,
+        cancel: Arc<Notify>,
+        config: AudioConfig,
+        profiles: ProfileConfig,
+    ) {
+        let _permit = tokio::select! {
+            permit = self.inner.workers.acquire() => match permit {
+                Ok(permit) => permit,
+                Err(_) => return,
+            },
+            _ = cancel.notified() => return,
+        };
+
+        let started = self.update(job.id, |entry| match entry.job.state {
+            JobState::Queued => {
+                entry.job.state = JobState::Running;
+                entry.job.progress = Some(0.0);
+                Ok(())
+            }
+            state => Err(anyhow!("job {} is {:?}", job.id, state)),
+        });
+
+        if started.is_err() {
+            return;
+        }
+
+        info!("starting job {} for {}", job.id, job.path.display());
+
+        let profile = profiles.resolve(job.profile.as_deref(), job.device.as_deref());
+
+        let result = tokio::select! {
+            result = self.transcode(&job, &config, &profiles, &profile) => result,
+            _ = cancel.notified() => {
+                info!("job {} was cancelled", job.id);
+                self.remove_part(&job.path, &profile).await;
+                return;
+            }
+        };
+
+        let _ = self.update(job.id, |entry| {
+            match result {
+                Ok(()) => {
+                    info!("job {} is done", job.id);
+                    entry.job.state = JobState::Done;
+                    entry.job.progress = Some(100.0);
+                }
+                Err(ref err) => {
+                    error!("job {} failed: {:#}", job.id, err);
+                    entry.job.state = JobState::Failed;
+                    entry.job.error = Some(format!("{:#}", err));
+                }
+            }
+
+            entry.job.eta = None;
+            Ok(())
+        });
+    }
+
+    async fn transcode(
+        &self,
+        job: &Job,
+        config: &AudioConfig,
+        profiles: &ProfileConfig,
+        profile: &Profile,
+    ) -> Result<(), Error> {
+        let path = job.path.as_path();
+        let info = probe::probe(path).await?;
+        let duration = info.duration.unwrap_or_default();
+        let query = StreamQuery::new(job.profile.as_deref(), job.device.as_deref());
+        let (verdict, options) = transcode::prepare(path, &query, config, profiles).await?;
+
+        let mode = match verdict {
+            Verdict::DirectPlay | Verdict::Remux => Mode::Remux,
+            Verdict::TranscodeAudio => Mode::TranscodeAudio,
+            Verdict::Downscale | Verdict::Transcode => Mode::Transcode,
+        };
+
+        let output = output_path(&self.inner.root, path, profile).await?;
+        let part = output.with_extension("part");
+        fs::create_dir_all(&self.inner.root).await?;
+
+        let args = transcode::file_args(path, &options, mode, &part);
+        debug!("job {} is {:?} to {}", job.id, mode, part.display());
+
+        let result = ffmpeg::run_with_progress(&args, |progress| {
+            let _ = self.update(job.id, |entry| {
+                entry.job.progress = percent(progress.time, duration);
+                entry.job.eta = eta(progress.time, duration, progress.speed);
+                Ok(())
+            });
+        })
+        .await;
+
+        match result {
+            Ok(()) => {
+                fs::rename(&part, &output)
+                    .await
+                    .with_context(|| format!("failed to move {}", part.display()))?;
+                Ok(())
+            }
+            Err(err) => {
+                self.remove_part(path, profile).await;
+                Err(err)
+            }
+        }
+    }
+
+    /// Deletes the partly transcoded file of the video at `path`.
+    async fn remove_part(&self, path: &Path, profile: &Profile) {
+        if let Ok(output) = output_path(&self.inner.root, path, profile).await {
+            let part = output.with_extension("part");
+
+            if let Err(err) = fs::remove_file(&part).await {
+                warn!("failed to delete {}: {}", part.display(), err);
+            }
+        }
+    }
+
+    fn update<F>(&self, id: u64, f: F) -> Result<Job, Error>
+    where
+        F: FnOnce(&mut Entry) -> Result<(), Error>,
+    {
+        let mut jobs = self.inner.jobs.lock().expect("jobs");
+
+        let entry = jobs
+            .iter_mut()
+            .find(|entry| entry.job.id == id)
+            .ok_or_else(|| anyhow!("job {} not found", id))?;
+
+        f(entry)?;
+        Ok(entry.job.clone())
+    }
+}
+
+/// The transcoded file of the video at `path` for receivers with the
+/// `profile`. A new file is used if the video or the profile changes.
+async fn output_path(root: &Path, path: &Path, profile: &Profile) -> Result<PathBuf, Error> {
+    let metadata = fs::metadata(path)
+        .await
+        .with_context(|| format!("failed to load metadata for file: {:#?}", path))?;
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+
+    let mut hash = Fnv::new();
+    hash.write(path.to_string_lossy().as_bytes());
+    hash.write(&metadata.len().to_le_bytes());
+    hash.write(&modified.as_secs().to_le_bytes());
+    hash.write(&modified.subsec_nanos().to_le_bytes());
+    // every field of the profile, so changing it in the config invalidates the file
+    hash.write(format!("{:?}", profile).as_bytes());
+
+    Ok(root.join(format!("{:016x}.mp4", hash.0)))
+}
+
+/// 64-bit FNV-1a, used for file names because it gives the same hash in
+/// every build, unlike `DefaultHasher`.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+fn percent(time: f64, duration: f64) -> Option<f64> {
+    if duration > 0.0 {
+        Some((time / duration * 100.0).clamp(0.0, 100.0))
+    } else {
+        None
+    }
+}
+
+fn eta(time: f64, duration: f64, speed: Option<f64>) -> Option<f64> {
+    match speed {
+        Some(speed) if speed > 0.0 && duration > 0.0 => Some((duration - time).max(0.0) / speed),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(30.0, 120.0 => Some(25.0); "when partly done")]
+    #[test_case(121.0, 120.0 => Some(100.0); "when past the end")]
+    #[test_case(30.0, 0.0 => None; "when duration is unknown")]
+    fn calculates_percent(time: f64, duration: f64) -> Option<f64> {
+        percent(time, duration)
+    }
+
+    #[test_case(30.0, 120.0, Some(2.0) => Some(45.0); "when speed is known")]
+    #[test_case(30.0, 120.0, Some(0.0) => None; "when stalled")]
+    #[test_case(30.0, 120.0, None => None; "when speed is unknown")]
+    fn calculates_eta(time: f64, duration: f64, speed: Option<f64>) -> Option<f64> {
+        eta(time, duration, speed)
+    }
+
+    #[test_case(b"" => 0xcbf2_9ce4_8422_2325; "when empty")]
+    #[test_case(b"a" => 0xaf63_dc4c_8601_ec8c; "when one byte")]
+    #[test_case(b"foobar" => 0x8594_4171_f739_67e8; "when several bytes")]
+    fn hashes_with_fnv(bytes: &[u8]) -> u64 {
+        let mut hash = Fnv::new();
+        hash.write(bytes);
+        hash.0
+    }
+
+    fn new_job(media: &str) -> NewJob {
+        NewJob {
+            media: media.to_owned(),
+            profile: None,
+            device: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn reuses_jobs_for_the_same_output() {
+        let path = std::env::temp_dir().join("videocaster-job-twice.mkv");
+        fs::write(&path, b"video").await.unwrap();
+        let jobs = JobQueue::new(std::env::temp_dir().join("videocaster-jobs"), 1);
+        let (config, profiles) = (AudioConfig::default(), ProfileConfig::default());
+
+        // keep the jobs queued
+        let _worker = jobs.inner.workers.acquire().await.unwrap();
+
+        let first = jobs
+            .enqueue(new_job("a"), path.clone(), &config, &profiles)
+            .await
+            .unwrap();
+        let second = jobs
+            .enqueue(new_job("a"), path.clone(), &config, &profiles)
+            .await
+            .unwrap();
+
+        assert_eq!(second.id, first.id);
+        assert_eq!(jobs.list().len(), 1);
+
+        fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_jobs_from_the_start() {
+        let path = std::env::temp_dir().join("videocaster-job-retry.mkv");
+        fs::write(&path, b"video").await.unwrap();
+        let jobs = JobQueue::new(std::env::temp_dir().join("videocaster-jobs"), 1);
+        let (config, profiles) = (AudioConfig::default(), ProfileConfig::default());
+        let _worker = jobs.inner.workers.acquire().await.unwrap();
+
+        let cancelled = jobs
+            .enqueue(new_job("a"), path.clone(), &config, &profiles)
+            .await
+            .unwrap();
+        jobs.cancel(cancelled.id).unwrap();
+        jobs.update(cancelled.id, |entry| {
+            entry.job.progress = Some(50.0);
+            entry.job.eta = Some(60.0);
+            Ok(())
+        })
+        .unwrap();
+
+        // a new job for the same file is queued, so the old one can't be retried
+        let queued = jobs
+            .enqueue(new_job("a"), path.clone(), &config, &profiles)
+            .await
+            .unwrap();
+        assert_ne!(queued.id, cancelled.id);
+        assert!(jobs.retry(cancelled.id, &config, &profiles).is_err());
+
+        jobs.cancel(queued.id).unwrap();
+        let retried = jobs.retry(cancelled.id, &config, &profiles).unwrap();
+        assert_eq!(retried.state, JobState::Queued);
+        assert_eq!(retried.progress, None);
+        assert_eq!(retried.eta, None);
+
+        fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_outputs_apart_by_profile() {
+        let path = std::env::temp_dir().join("videocaster-job-profile.mkv");
+        fs::write(&path, b"video").await.unwrap();
+        let root = Path::new("/jobs");
+
+        let chromecast = output_path(root, &path, &Profile::default()).await.unwrap();
+        let ultra = Profile {
+            max_width: 3840,
+            max_height: 2160,
+            ..Profile::default()
+        };
+
+        assert_eq!(
+            output_path(root, &path, &Profile::default()).await.unwrap(),
+            chromecast
+        );
+        assert_ne!(output_path(root, &path, &ultra).await.unwrap(), chromecast);
+    }
+}
@@ -1,5 +1,14 @@
 //! These modules provide handlers for the Chromecast to connect to
 //! such as loading videos and downloading subtitles.
+pub(crate) mod audio;
+mod burn_in;
+mod conditional;
+pub(crate) mod hls;
+pub(crate) mod jobs;
+mod multipart;
 mod range;
+pub(crate) mod remux;
 pub(crate) mod subtitles;
+pub(crate) mod traffic;
+pub(crate) mod transcode;
 pub(crate) mod video;
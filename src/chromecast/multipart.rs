@@ -0,0 +1,228 @@
+//! `multipart/byteranges` response bodies as described in RFC 7233, appendix A.
+use super::range::HttpRange;
+use futures::ready;
+use rocket::http::ContentType;
+use std::{
+    io::{Error as IoError, ErrorKind, Result as IoResult, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+const BUF_SIZE: usize = 64 * 1024;
+
+enum Segment {
+    /// Part headers, separators and the closing delimiter.
+    Bytes(Vec<u8>),
+
+    /// A range of the underlying reader.
+    Range(HttpRange),
+}
+
+/// Reads each range from `reader` in turn and wraps them in multipart
+/// boundaries with a `Content-Range` header per part.
+pub(crate) struct MultipartRanges<R> {
+    reader: R,
+    segments: Vec<Segment>,
+    index: usize,
+    position: u64,
+    seek: SeekState,
+    buf: Vec<u8>,
+}
+
+#[derive(PartialEq)]
+enum SeekState {
+    Pending,
+    Seeking,
+    Done,
+}
+
+impl<R> MultipartRanges<R> {
+    pub(crate) fn new(
+        reader: R,
+        ranges: &[HttpRange],
+        size: u64,
+        content_type: Option<&ContentType>,
+        boundary: &str,
+    ) -> Self {
+        let mut segments = Vec::with_capacity(ranges.len() * 2 + 1);
+
+        for (i, range) in ranges.iter().enumerate() {
+            let mut header = String::new();
+
+            if i > 0 {
+                header.push_str("\r\n");
+            }
+
+            header.push_str(&format!("--{}\r\n", boundary));
+
+            if let Some(content_type) = content_type {
+                header.push_str(&format!("Content-Type: {}\r\n", content_type));
+            }
+
+            header.push_str(&format!(
+                "Content-Range: bytes {}-{}/{}\r\n\r\n",
+                range.start,
+                range.end(),
+                size
+            ));
+
+            segments.push(Segment::Bytes(header.into_bytes()));
+            segments.push(Segment::Range(*range));
+        }
+
+        segments.push(Segment::Bytes(
+            format!("\r\n--{}--\r\n", boundary).into_bytes(),
+        ));
+
+        Self {
+            reader,
+            segments,
+            index: 0,
+            position: 0,
+            seek: SeekState::Pending,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Total length of the body in bytes.
+    pub(crate) fn len(&self) -> u64 {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Bytes(bytes) => bytes.len() as u64,
+                Segment::Range(range) => range.length,
+            })
+            .sum()
+    }
+
+    fn next_segment(&mut self) {
+        self.index += 1;
+        self.position = 0;
+        self.seek = SeekState::Pending;
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncRead for MultipartRanges<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            let range = match this.segments.get(this.index) {
+                None => return Poll::Ready(Ok(())),
+                Some(Segment::Bytes(bytes)) => {
+                    let rest = &bytes[this.position as usize..];
+
+                    if rest.is_empty() {
+                        this.next_segment();
+                        continue;
+                    }
+
+                    let n = rest.len().min(buf.remaining());
+                    buf.put_slice(&rest[..n]);
+                    this.position += n as u64;
+                    return Poll::Ready(Ok(()));
+                }
+                Some(Segment::Range(range)) => *range,
+            };
+
+            if this.seek != SeekState::Done {
+                if this.seek == SeekState::Pending {
+                    let position = SeekFrom::Start(range.start);
+                    Pin::new(&mut this.reader).start_seek(position)?;
+                    this.seek = SeekState::Seeking;
+                }
+
+                ready!(Pin::new(&mut this.reader).poll_complete(cx))?;
+                this.seek = SeekState::Done;
+            }
+
+            let remaining = range.length - this.position;
+
+            if remaining == 0 {
+                this.next_segment();
+                continue;
+            }
+
+            let max = remaining.min(buf.remaining().min(BUF_SIZE) as u64) as usize;
+            this.buf.resize(max, 0);
+            let mut inner = ReadBuf::new(&mut this.buf[..max]);
+            ready!(Pin::new(&mut this.reader).poll_read(cx, &mut inner))?;
+            let filled = inner.filled();
+
+            if filled.is_empty() {
+                let msg = "file ended before the requested range";
+                return Poll::Ready(Err(IoError::new(ErrorKind::UnexpectedEof, msg)));
+            }
+
+            buf.put_slice(filled);
+            this.position += filled.len() as u64;
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+/// Creates a boundary that is unlikely to show up in the file contents.
+pub(crate) fn boundary() -> String {
+    let nanos = SystemTime::UNIX_EPOCH
+        .elapsed()
+        .unwrap_or_default()
+        .as_nanos();
+
+    format!("videocaster{:032x}", nanos)
+}
+
+pub(crate) fn content_type(boundary: &str) -> ContentType {
+    ContentType::with_params("multipart", "byteranges", ("boundary", boundary.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn writes_parts() {
+        let data: Vec<u8> = (b'a'..=b'z').collect();
+        let ranges = [
+            HttpRange {
+                start: 0,
+                length: 1,
+            },
+            HttpRange {
+                start: 23,
+                length: 3,
+            },
+        ];
+
+        let content_type = ContentType::MP4;
+        let mut body =
+            MultipartRanges::new(Cursor::new(data), &ranges, 26, Some(&content_type), "b");
+        let len = body.len();
+        let mut out = String::new();
+        body.read_to_string(&mut out).await.unwrap();
+
+        let expected = "--b\r\n\
+            Content-Type: video/mp4\r\n\
+            Content-Range: bytes 0-0/26\r\n\r\n\
+            a\r\n\
+            --b\r\n\
+            Content-Type: video/mp4\r\n\
+            Content-Range: bytes 23-25/26\r\n\r\n\
+            xyz\r\n\
+            --b--\r\n";
+
+        assert_eq!(out, expected);
+        assert_eq!(len, expected.len() as u64);
+    }
+}
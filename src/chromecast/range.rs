@@ -3,7 +3,7 @@ use std::num::ParseIntError;
 use thiserror::Error;
 
 /// HTTP Range header representation.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) struct HttpRange {
     /// Start of range.
     pub(crate) start: u64,
@@ -31,11 +31,17 @@ pub(crate) enum HttpRangeParseError {
 
     #[error("No overlap and no ranges found")]
     NoRanges,
+
+    #[error("Too many ranges, at most {} are allowed", MAX_RANGES)]
+    TooManyRanges,
 }
 
 const PREFIX: &str = "bytes=";
 const PREFIX_LEN: usize = 6;
 
+/// Maximum number of ranges a single Range header may ask for.
+pub(crate) const MAX_RANGES: usize = 16;
+
 impl HttpRange {
     /// Parses Range HTTP header string as per RFC 2616.
     ///
@@ -114,6 +120,10 @@ impl HttpRange {
             })
             .collect::<Result<_, HttpRangeParseError>>()?;
 
+        if all_ranges.len() > MAX_RANGES {
+            return Err(TooManyRanges);
+        }
+
         let ranges: Vec<HttpRange> = all_ranges.into_iter().flatten().collect();
 
         if no_overlap && ranges.is_empty() {
@@ -122,6 +132,32 @@ impl HttpRange {
 
         Ok(ranges)
     }
+
+    /// Sorts ranges by start and merges the ones that overlap or are adjacent.
+    /// Empty ranges are dropped.
+    pub fn merge(mut ranges: Vec<HttpRange>) -> Vec<HttpRange> {
+        ranges.retain(|range| range.length > 0);
+        ranges.sort_by_key(|range| range.start);
+
+        let mut merged: Vec<HttpRange> = Vec::with_capacity(ranges.len());
+
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.start + last.length => {
+                    let end = (last.start + last.length).max(range.start + range.length);
+                    last.length = end - last.start;
+                }
+                _ => merged.push(range),
+            }
+        }
+
+        merged
+    }
+
+    /// Inclusive end of range, as used in the Content-Range header.
+    pub fn end(&self) -> u64 {
+        self.start + self.length - 1
+    }
 }
 
 #[cfg(test)]
@@ -398,4 +434,38 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_parse_too_many_ranges() {
+        let header = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        let res = HttpRange::parse(&header, 10);
+        assert!(matches!(res, Err(HttpRangeParseError::TooManyRanges)));
+    }
+
+    #[test]
+    fn test_merge() {
+        fn r(start: u64, length: u64) -> HttpRange {
+            HttpRange { start, length }
+        }
+
+        let tests = vec![
+            (vec![], vec![]),
+            (vec![r(0, 1)], vec![r(0, 1)]),
+            (vec![r(0, 1), r(9999, 1)], vec![r(0, 1), r(9999, 1)]),
+            (vec![r(9999, 1), r(0, 1)], vec![r(0, 1), r(9999, 1)]),
+            // adjacent
+            (vec![r(500, 101), r(601, 399)], vec![r(500, 500)]),
+            // overlapping
+            (vec![r(500, 201), r(601, 399)], vec![r(500, 500)]),
+            // contained
+            (vec![r(0, 100), r(10, 10)], vec![r(0, 100)]),
+            // empty
+            (vec![r(10, 0), r(0, 5)], vec![r(0, 5)]),
+        ];
+
+        for (ranges, expected) in tests {
+            let input = format!("{:?}", ranges);
+            assert_eq!(HttpRange::merge(ranges), expected, "merge({})", input);
+        }
+    }
 }
@@ -0,0 +1,59 @@
+//! Remuxing changes the container of a video without re-encoding it,
+//! which costs almost no CPU compared to transcoding. This is enough
+//! for e.g. MKV files with H.264 video and AAC audio.
+use super::{
+    audio::AudioConfig,
+    transcode::{self, Mode, StreamHead, StreamQuery, TranscodeResponder},
+};
+use crate::{
+    app_result::AppResult, auth::MediaAccess, library::Library, media::MediaRegistry,
+    profile::ProfileConfig,
+};
+use rocket::{get, head, State};
+
+#[get("/remux/<id>?<query..>")]
+pub(crate) async fn handler(
+    _access: MediaAccess,
+    id: &str,
+    query: StreamQuery<'_>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> Result<TranscodeResponder, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(TranscodeResponder::not_found()),
+    };
+
+    if !path.is_file() {
+        return Ok(TranscodeResponder::not_found());
+    }
+
+    let (_, options) = transcode::prepare(&path, &query, config, profiles).await?;
+    Ok(transcode::stream(&path, &options, Mode::Remux).await?)
+}
+
+/// The headers `handler` would respond with, without starting ffmpeg.
+#[head("/remux/<id>?<query..>")]
+pub(crate) async fn head(
+    _access: MediaAccess,
+    id: &str,
+    query: StreamQuery<'_>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> Result<StreamHead, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(StreamHead::not_found()),
+    };
+
+    if !path.is_file() {
+        return Ok(StreamHead::not_found());
+    }
+
+    let (_, options) = transcode::prepare(&path, &query, config, profiles).await?;
+    Ok(transcode::stream_head(&path, &options, Mode::Remux).await)
+}
@@ -1,4 +1,4 @@
-use crate::opensubs;
+use crate::{auth::MediaAccess, opensubs};
 use anyhow::Error;
 use rocket::{
     get,
@@ -7,7 +7,10 @@ use rocket::{
 };
 
 #[get("/subtitles/download/<url>")]
-pub(crate) async fn handler(url: &str) -> Result<Custom<String>, Debug<Error>> {
+pub(crate) async fn handler(
+    _access: MediaAccess,
+    url: &str,
+) -> Result<Custom<String>, Debug<Error>> {
     let vtt = opensubs::download_subtitle(url).await?;
     Ok(Custom(ContentType::new("text", "vtt"), vtt))
 }
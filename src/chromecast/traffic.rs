@@ -0,0 +1,337 @@
+//! Counts and optionally limits the bytes sent by each stream, so e.g. a
+//! high bitrate remux doesn't use all of the Wi-Fi. Streams are listed with
+//! their client and current rate at `/streams`.
+use crate::{app_result::AppResult, auth::Paired};
+use log::info;
+use rocket::{get, State};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The smallest read when limited, so slow rates don't cause tiny reads.
+const MIN_GRANT: f64 = 16.0 * 1024.0;
+
+/// How long the current rate is measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct TrafficConfig {
+    /// Bandwidth cap of each stream in megabits per second.
+    #[serde(default)]
+    pub(crate) max_stream_mbps: Option<f64>,
+
+    /// Bandwidth cap of all streams together in megabits per second.
+    #[serde(default)]
+    pub(crate) max_total_mbps: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) struct StreamInfo {
+    id: u64,
+    client: Option<IpAddr>,
+    file: String,
+    bytes_sent: u64,
+
+    /// Bytes per second over the last second.
+    rate: f64,
+
+    /// Seconds since the stream started.
+    duration: f64,
+}
+
+#[get("/streams")]
+pub(crate) async fn handler(
+    _paired: Paired,
+    traffic: &State<Traffic>,
+) -> AppResult<Vec<StreamInfo>> {
+    AppResult::success(traffic.streams())
+}
+
+/// The streams being sent and the global bandwidth limit.
+#[derive(Clone)]
+pub(crate) struct Traffic {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    next_id: AtomicU64,
+    streams: Mutex<HashMap<u64, Arc<Stats>>>,
+    total_limit: Option<Mutex<Limiter>>,
+    stream_limit: Option<f64>,
+}
+
+impl Traffic {
+    pub(crate) fn new(config: &TrafficConfig) -> Self {
+        let stream_limit = config.max_stream_mbps.and_then(bytes_per_sec);
+        let total_limit = config.max_total_mbps.and_then(bytes_per_sec);
+        info!(
+            "bandwidth limits: {:?} B/s per stream, {:?} B/s in total",
+            stream_limit, total_limit
+        );
+
+        Self {
+            inner: Arc::new(Inner {
+                next_id: AtomicU64::new(1),
+                streams: Mutex::default(),
+                total_limit: total_limit.map(|rate| Mutex::new(Limiter::new(rate, Instant::now()))),
+                stream_limit,
+            }),
+        }
+    }
+
+    /// Registers a stream of `file` to `client` until the meter is dropped.
+    pub(super) fn meter(&self, client: Option<IpAddr>, file: String) -> Meter {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+
+        let stats = Arc::new(Stats {
+            id,
+            client,
+            file,
+            started: now,
+            bytes: AtomicU64::new(0),
+            rate: Mutex::new(Rate {
+                window_start: now,
+                window_bytes: 0,
+                rate: 0.0,
+            }),
+        });
+
+        let mut streams = self.inner.streams.lock().expect("streams");
+        streams.insert(id, stats.clone());
+
+        Meter {
+            traffic: self.clone(),
+            stats,
+            limiter: self.inner.stream_limit.map(|rate| Limiter::new(rate, now)),
+            scratch: Vec::new(),
+        }
+    }
+
+    fn streams(&self) -> Vec<StreamInfo> {
+        let streams = self.inner.streams.lock().expect("streams");
+        let mut streams: Vec<StreamInfo> = streams.values().map(|stats| stats.info()).collect();
+        streams.sort_by_key(|stream| stream.id);
+        streams
+    }
+}
+
+struct Stats {
+    id: u64,
+    client: Option<IpAddr>,
+    file: String,
+    started: Instant,
+    bytes: AtomicU64,
+    rate: Mutex<Rate>,
+}
+
+struct Rate {
+    window_start: Instant,
+    window_bytes: u64,
+    rate: f64,
+}
+
+impl Stats {
+    fn record(&self, bytes: usize, now: Instant) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+
+        let mut rate = self.rate.lock().expect("stream rate");
+        rate.window_bytes += bytes as u64;
+        let elapsed = now.saturating_duration_since(rate.window_start);
+
+        if elapsed >= RATE_WINDOW {
+            rate.rate = rate.window_bytes as f64 / elapsed.as_secs_f64();
+            rate.window_start = now;
+            rate.window_bytes = 0;
+        }
+    }
+
+    fn info(&self) -> StreamInfo {
+        let rate = self.rate.lock().expect("stream rate");
+
+        // the rate drops to zero if nothing has been sent for a while, e.g. when paused
+        let rate = if rate.window_start.elapsed() > RATE_WINDOW * 2 {
+            0.0
+        } else {
+            rate.rate
+        };
+
+        StreamInfo {
+            id: self.id,
+            client: self.client,
+            file: self.file.clone(),
+            bytes_sent: self.bytes.load(Ordering::Relaxed),
+            rate,
+            duration: self.started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// Counts the bytes of one stream and decides how many may be read
+/// next, see `FileWrapper`.
+pub(super) struct Meter {
+    traffic: Traffic,
+    stats: Arc<Stats>,
+    limiter: Option<Limiter>,
+
+    /// Reads are made into this buffer when limited.
+    pub(super) scratch: Vec<u8>,
+}
+
+impl Meter {
+    pub(super) fn is_limited(&self) -> bool {
+        self.limiter.is_some() || self.traffic.inner.total_limit.is_some()
+    }
+
+    /// Returns how many of the `wanted` bytes may be read now, or how long
+    /// to wait before asking again.
+    pub(super) fn allowance(&mut self, wanted: usize) -> Result<usize, Duration> {
+        let now = Instant::now();
+        let mut allowed = wanted;
+
+        if let Some(limiter) = &mut self.limiter {
+            allowed = limiter.allowance(now, allowed)?;
+        }
+
+        if let Some(total) = &self.traffic.inner.total_limit {
+            allowed = total.lock().expect("total limit").allowance(now, allowed)?;
+        }
+
+        Ok(allowed)
+    }
+
+    /// Records that `bytes` were sent.
+    pub(super) fn record(&mut self, bytes: usize) {
+        if let Some(limiter) = &mut self.limiter {
+            limiter.consume(bytes);
+        }
+
+        if let Some(total) = &self.traffic.inner.total_limit {
+            total.lock().expect("total limit").consume(bytes);
+        }
+
+        self.stats.record(bytes, Instant::now());
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        let mut streams = self.traffic.inner.streams.lock().expect("streams");
+        streams.remove(&self.stats.id);
+    }
+}
+
+/// A token bucket allowing `rate` bytes per second with short bursts.
+struct Limiter {
+    rate: f64,
+    capacity: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl Limiter {
+    fn new(rate: f64, now: Instant) -> Self {
+        let capacity = (rate / 4.0).max(MIN_GRANT);
+
+        Self {
+            rate,
+            capacity,
+            available: capacity,
+            updated: now,
+        }
+    }
+
+    fn allowance(&mut self, now: Instant, wanted: usize) -> Result<usize, Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+
+        let wanted = wanted as f64;
+        let minimum = wanted.min(MIN_GRANT);
+
+        if self.available >= minimum {
+            Ok(wanted.min(self.available) as usize)
+        } else {
+            Err(Duration::from_secs_f64(
+                (minimum - self.available) / self.rate,
+            ))
+        }
+    }
+
+    /// The balance may go below zero when streams share the limiter.
+    fn consume(&mut self, bytes: usize) {
+        self.available -= bytes as f64;
+    }
+}
+
+fn bytes_per_sec(mbps: f64) -> Option<f64> {
+    Some(mbps * 1_000_000.0 / 8.0).filter(|rate| *rate > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limiter_allows_bursts_up_to_capacity() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(1_000_000.0, now);
+
+        assert_eq!(limiter.allowance(now, 1_000_000), Ok(250_000));
+        limiter.consume(250_000);
+        assert!(limiter.allowance(now, 1_000_000).is_err());
+    }
+
+    #[test]
+    fn limiter_refills_at_rate() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(1_000_000.0, now);
+        limiter.consume(250_000);
+
+        let wait = limiter.allowance(now, 100_000).unwrap_err();
+        assert_eq!(wait, Duration::from_secs_f64(MIN_GRANT / 1_000_000.0));
+
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.allowance(later, 1_000_000), Ok(100_000));
+    }
+
+    #[test]
+    fn limiter_waits_for_overdraft() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(100_000.0, now);
+        limiter.consume(75_000);
+
+        let wait = limiter.allowance(now, 4096).unwrap_err();
+        assert_eq!(wait, Duration::from_secs_f64(54_096.0 / 100_000.0));
+    }
+
+    #[test]
+    fn converts_megabits() {
+        assert_eq!(bytes_per_sec(8.0), Some(1_000_000.0));
+        assert_eq!(bytes_per_sec(0.0), None);
+    }
+
+    #[test]
+    fn lists_streams_until_dropped() {
+        let traffic = Traffic::new(&TrafficConfig::default());
+        let mut meter = traffic.meter(None, "a.mkv".to_owned());
+        assert!(!meter.is_limited());
+        meter.record(1000);
+
+        let streams = traffic.streams();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].file, "a.mkv");
+        assert_eq!(streams[0].bytes_sent, 1000);
+
+        drop(meter);
+        assert!(traffic.streams().is_empty());
+    }
+}
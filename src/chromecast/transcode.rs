@@ -0,0 +1,674 @@
+//! Converts videos the Chromecast can't play into fragmented MP4
+//! with ffmpeg while they are being streamed. Streams are copied
+//! when possible and transcoded to H.264 and AAC or AC3 otherwise.
+use super::{
+    audio::{self, AudioCodec, AudioConfig},
+    burn_in::{self, BurnIn},
+    video::FileWrapper,
+};
+use crate::{
+    app_result::AppResult,
+    auth::MediaAccess,
+    ffmpeg::{self, FfmpegStream},
+    library::Library,
+    media::MediaRegistry,
+    probe::{self, MediaInfo, Verdict},
+    profile::ProfileConfig,
+};
+use anyhow::Error;
+use log::{debug, info, warn};
+use rocket::{
+    get, head,
+    http::{ContentType, Header, Status},
+    response::{Builder as ResponseBuilder, Responder, Result as RocketResult},
+    FromForm, Request, Response, State,
+};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+/// How the streams of a file are converted to fragmented MP4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Mode {
+    /// Copy the streams into the new container without re-encoding.
+    Remux,
+
+    /// Copy the video stream and re-encode the audio stream.
+    TranscodeAudio,
+
+    /// Re-encode the streams to H.264/AAC.
+    Transcode,
+}
+
+impl Mode {
+    /// How a file is streamed to play it on the receiver.
+    fn for_verdict(verdict: Verdict) -> Self {
+        match verdict {
+            Verdict::DirectPlay | Verdict::Remux => Mode::Remux,
+            Verdict::TranscodeAudio => Mode::TranscodeAudio,
+            Verdict::Downscale | Verdict::Transcode => Mode::Transcode,
+        }
+    }
+}
+
+/// Query parameters of the streaming endpoints.
+#[derive(Debug, FromForm)]
+pub(crate) struct StreamQuery<'r> {
+    /// Where to start in seconds.
+    pub(super) t: Option<f64>,
+
+    /// Stream index of the audio track to include.
+    pub(super) audio: Option<u32>,
+
+    /// Preferred audio language, used if `audio` is not given.
+    pub(super) lang: Option<&'r str>,
+
+    /// What to encode audio to if it must be transcoded.
+    pub(super) audio_codec: Option<AudioCodec>,
+
+    /// Stream index of the subtitle track to burn into the video.
+    pub(super) subtitle: Option<u32>,
+
+    /// Name of the capability profile of the receiver.
+    pub(super) profile: Option<&'r str>,
+
+    /// Name of the receiver, used to look up its profile if `profile` is not given.
+    pub(super) device: Option<&'r str>,
+}
+
+impl<'r> StreamQuery<'r> {
+    /// A query for the whole file with the default tracks for the receiver.
+    pub(super) fn new(profile: Option<&'r str>, device: Option<&'r str>) -> Self {
+        Self {
+            t: None,
+            audio: None,
+            lang: None,
+            audio_codec: None,
+            subtitle: None,
+            profile,
+            device,
+        }
+    }
+}
+
+/// What to read from the input file and how to encode it.
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct Options {
+    /// Where to start in seconds.
+    pub(super) start: Option<f64>,
+
+    /// Stream index of the audio track to include. The first one is used if `None`.
+    pub(super) audio: Option<u32>,
+
+    /// Number of channels in the audio track.
+    pub(super) audio_channels: Option<u32>,
+
+    pub(super) audio_codec: AudioCodec,
+
+    /// Subtitles to burn into the video, only when transcoding.
+    pub(super) burn_in: Option<BurnIn>,
+
+    /// Width and height to scale the video down to fit, only when transcoding.
+    pub(super) max_size: Option<(u32, u32)>,
+
+    /// Whether to convert HDR video to SDR, only when transcoding.
+    pub(super) tone_map: bool,
+}
+
+/// Streams the file with as little re-encoding as possible.
+#[get("/transcode/<id>?<query..>")]
+pub(crate) async fn handler(
+    _access: MediaAccess,
+    id: &str,
+    query: StreamQuery<'_>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> Result<TranscodeResponder, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(TranscodeResponder::not_found()),
+    };
+
+    if !path.is_file() {
+        return Ok(TranscodeResponder::not_found());
+    }
+
+    let (verdict, options) = prepare(&path, &query, config, profiles).await?;
+    Ok(stream(&path, &options, Mode::for_verdict(verdict)).await?)
+}
+
+/// The headers `handler` would respond with, without starting ffmpeg.
+#[head("/transcode/<id>?<query..>")]
+pub(crate) async fn head(
+    _access: MediaAccess,
+    id: &str,
+    query: StreamQuery<'_>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> Result<StreamHead, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(StreamHead::not_found()),
+    };
+
+    if !path.is_file() {
+        return Ok(StreamHead::not_found());
+    }
+
+    let (verdict, options) = prepare(&path, &query, config, profiles).await?;
+    Ok(stream_head(&path, &options, Mode::for_verdict(verdict)).await)
+}
+
+/// Copies the video stream and transcodes the audio stream, e.g. for
+/// Blu-ray rips with H.264 video and DTS or TrueHD audio.
+#[get("/transcode/audio/<id>?<query..>")]
+pub(crate) async fn audio_handler(
+    _access: MediaAccess,
+    id: &str,
+    query: StreamQuery<'_>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> Result<TranscodeResponder, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(TranscodeResponder::not_found()),
+    };
+
+    if !path.is_file() {
+        return Ok(TranscodeResponder::not_found());
+    }
+
+    let (_, options) = prepare(&path, &query, config, profiles).await?;
+    Ok(stream(&path, &options, Mode::TranscodeAudio).await?)
+}
+
+/// The headers `audio_handler` would respond with, without starting ffmpeg.
+#[head("/transcode/audio/<id>?<query..>")]
+pub(crate) async fn audio_head(
+    _access: MediaAccess,
+    id: &str,
+    query: StreamQuery<'_>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    config: &State<AudioConfig>,
+    profiles: &State<ProfileConfig>,
+) -> Result<StreamHead, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(StreamHead::not_found()),
+    };
+
+    if !path.is_file() {
+        return Ok(StreamHead::not_found());
+    }
+
+    let (_, options) = prepare(&path, &query, config, profiles).await?;
+    Ok(stream_head(&path, &options, Mode::TranscodeAudio).await)
+}
+
+/// Probes the file, selects the audio track and decides how it can be
+/// played on the receiver.
+pub(super) async fn prepare(
+    path: &Path,
+    query: &StreamQuery<'_>,
+    config: &AudioConfig,
+    profiles: &ProfileConfig,
+) -> Result<(Verdict, Options), Error> {
+    let info = probe::probe(path).await?;
+    Ok(options(&info, query, config, profiles))
+}
+
+/// Selects the audio track of the probed file and decides how it can be
+/// played on the receiver.
+pub(super) fn options(
+    info: &MediaInfo,
+    query: &StreamQuery<'_>,
+    config: &AudioConfig,
+    profiles: &ProfileConfig,
+) -> (Verdict, Options) {
+    let track = audio::select(&info.audio, query.audio, query.lang, config);
+    info!("selected audio track: {:?}", track);
+
+    let profile = profiles.resolve(query.profile, query.device);
+    debug!("receiver profile: {:?}", profile);
+
+    let burn_in = query
+        .subtitle
+        .and_then(|index| burn_in::select(&info.subtitles, index));
+
+    let verdict = match burn_in {
+        // the video must be re-encoded to draw the subtitles onto it
+        Some(_) => Verdict::Transcode,
+        None => profile.verdict(info, track),
+    };
+
+    let video = info.video.first();
+    let max_size = video.and_then(|video| profile.transcode_size(video));
+    let tone_map = matches!(video, Some(video) if profile.tone_maps(video));
+
+    let options = Options {
+        start: query.t.filter(|t| *t > 0.0),
+        audio: track.map(|track| track.index),
+        audio_channels: track.and_then(|track| track.channels),
+        audio_codec: query.audio_codec.unwrap_or_default(),
+        burn_in,
+        max_size,
+        tone_map,
+    };
+
+    (verdict, options)
+}
+
+pub(super) async fn stream(
+    path: &Path,
+    options: &Options,
+    mode: Mode,
+) -> Result<TranscodeResponder, Error> {
+    let options = for_mode(options, mode);
+    let start = start_time(path, &options, mode).await;
+    info!(
+        "{:?} {} from {} s with {:?}",
+        mode,
+        path.display(),
+        start,
+        options
+    );
+    let stream = ffmpeg::spawn(&ffmpeg_args(path, &options, mode))?;
+    Ok(TranscodeResponder {
+        path: path.to_path_buf(),
+        stream: Some(stream),
+        start,
+    })
+}
+
+/// Finds where the stream would start without streaming it. Rocket answers
+/// HEAD requests with the GET route otherwise, which would start ffmpeg
+/// only to drop its output.
+pub(super) async fn stream_head(path: &Path, options: &Options, mode: Mode) -> StreamHead {
+    let options = for_mode(options, mode);
+    StreamHead {
+        start: Some(start_time(path, &options, mode).await),
+    }
+}
+
+/// Returns `options` without the video filters if the video is copied.
+fn for_mode(options: &Options, mode: Mode) -> Options {
+    let mut options = *options;
+
+    if mode != Mode::Transcode {
+        if options.burn_in.take().is_some() {
+            warn!("subtitles can only be burned in when transcoding");
+        }
+
+        options.max_size = None;
+        options.tone_map = false;
+    }
+
+    options
+}
+
+/// Returns where the output starts in the input in seconds. Transcoding
+/// starts exactly at `start`, but copying starts at the keyframe before it.
+async fn start_time(path: &Path, options: &Options, mode: Mode) -> f64 {
+    let start = match options.start {
+        Some(start) => start,
+        None => return 0.0,
+    };
+
+    if mode == Mode::Transcode {
+        return start;
+    }
+
+    match ffmpeg::keyframe_before(path, start).await {
+        Ok(Some(keyframe)) => keyframe.max(0.0),
+        Ok(None) => start,
+        Err(err) => {
+            warn!("failed to find keyframe before {} s: {}", start, err);
+            start
+        }
+    }
+}
+
+pub(crate) struct TranscodeResponder {
+    path: PathBuf,
+    stream: Option<FfmpegStream>,
+    start: f64,
+}
+
+impl TranscodeResponder {
+    pub(super) fn not_found() -> Self {
+        Self {
+            path: PathBuf::new(),
+            stream: None,
+            start: 0.0,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TranscodeResponder {
+    fn respond_to(self, request: &'r Request<'_>) -> RocketResult<'static> {
+        let mut response = Response::build();
+
+        if let Some(stream) = self.stream {
+            stream_headers(&mut response, self.start);
+            response.streamed_body(FileWrapper::new(stream).metered(request, &self.path));
+        } else {
+            response.status(Status::NotFound);
+        }
+
+        Ok(response.finalize())
+    }
+}
+
+/// The response to a HEAD request for a stream.
+pub(crate) struct StreamHead {
+    start: Option<f64>,
+}
+
+impl StreamHead {
+    pub(super) fn not_found() -> Self {
+        Self { start: None }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for StreamHead {
+    fn respond_to(self, _request: &'r Request<'_>) -> RocketResult<'static> {
+        let mut response = Response::build();
+
+        match self.start {
+            Some(start) => stream_headers(&mut response, start),
+            None => {
+                response.status(Status::NotFound);
+            }
+        }
+
+        Ok(response.finalize())
+    }
+}
+
+fn stream_headers(response: &mut ResponseBuilder<'_>, start: f64) {
+    response.header(ContentType::MP4);
+    // the output size is unknown, so seeking is done with `t` instead
+    response.header(Header::new("Accept-Ranges", "none"));
+    // where playback starts in the file, to offset the playback position
+    response.header(Header::new(START_TIME_HEADER, format!("{:.3}", start)));
+}
+
+/// The position in seconds in the file at which the streamed output
+/// starts, which is where the `t` parameter seeks to when transcoding,
+/// and the keyframe before it when copying the video.
+pub(crate) const START_TIME_HEADER: &str = "X-Start-Time";
+
+fn ffmpeg_args(path: &Path, options: &Options, mode: Mode) -> Vec<OsString> {
+    let mut args = mode_args(path, options, mode);
+
+    let output = [
+        "-movflags",                                  // make mp4 streamable by writing
+        "frag_keyframe+empty_moov+default_base_moof", // fragments
+        "-f",                                         // set output format to
+        "mp4",                                        // mp4
+        "-",                                          // pipe to stdout
+    ];
+
+    args.extend(output.iter().map(OsString::from));
+    args
+}
+
+/// Arguments for writing a regular MP4 file to `output`, which can be
+/// served with range requests once it is complete.
+pub(super) fn file_args(
+    path: &Path,
+    options: &Options,
+    mode: Mode,
+    output: &Path,
+) -> Vec<OsString> {
+    let options = for_mode(options, mode);
+    let mut args = mode_args(path, &options, mode);
+
+    let file = [
+        "-movflags",  // make mp4 playable before it is fully downloaded
+        "+faststart", // by moving the index to the start
+        "-f",         // set output format to
+        "mp4",        // mp4
+        "-y",         // overwrite the output file
+    ];
+
+    args.extend(file.iter().map(OsString::from));
+    args.push(output.into());
+    args
+}
+
+fn mode_args(path: &Path, options: &Options, mode: Mode) -> Vec<OsString> {
+    match mode {
+        Mode::Remux => copy_args(path, options),
+        Mode::TranscodeAudio => copy_video_args(path, options),
+        Mode::Transcode => encode_args(path, options),
+    }
+}
+
+/// Arguments for reading `path` and copying the streams as is.
+/// The output options are left to the caller.
+fn copy_args(path: &Path, options: &Options) -> Vec<OsString> {
+    let mut args = input_args(path, options);
+
+    let copying = [
+        "-c",   // copy all streams
+        "copy", // without re-encoding
+    ];
+
+    args.extend(copying.iter().map(OsString::from));
+    args.extend(LOG_ARGS.iter().map(OsString::from));
+    args
+}
+
+/// Arguments for reading `path`, copying the video stream as is and
+/// encoding the audio stream. The output options are left to the caller.
+fn copy_video_args(path: &Path, options: &Options) -> Vec<OsString> {
+    let mut args = input_args(path, options);
+
+    let copying = [
+        "-c:v", // copy the video stream
+        "copy", // without re-encoding
+    ];
+
+    args.extend(copying.iter().map(OsString::from));
+    args.extend(audio_args(options));
+    args.extend(LOG_ARGS.iter().map(OsString::from));
+    args
+}
+
+/// Arguments for reading `path` and encoding it to H.264 and AAC or AC3.
+/// The output options are left to the caller.
+pub(super) fn encode_args(path: &Path, options: &Options) -> Vec<OsString> {
+    let mut args = input_args(path, options);
+
+    let encoding = [
+        "-c:v",       // encode video with
+        "libx264",    // H.264
+        "-preset",    // set encoding speed to
+        "veryfast",   // keep up with playback on weak machines
+        "-profile:v", // set H.264 profile to
+        "high",       // high, supported by all Chromecasts
+        "-level:v",   // set H.264 level to
+        "4.1",        // 1080p at 30 fps
+        "-pix_fmt",   // set pixel format to
+        "yuv420p",    // 8 bit 4:2:0
+    ];
+
+    args.extend(encoding.iter().map(OsString::from));
+    args.extend(audio_args(options));
+    args.extend(LOG_ARGS.iter().map(OsString::from));
+    args
+}
+
+const LOG_ARGS: [&str; 4] = [
+    "-nostats",     // hide stats from stderr
+    "-hide_banner", // hide banner from stderr
+    "-loglevel",    // only log
+    "error",        // errors
+];
+
+fn audio_args(options: &Options) -> Vec<OsString> {
+    let (codec, channels, bitrate) = match options.audio_codec {
+        AudioCodec::Aac => ("aac", 2, "192k"),
+        // AC3 supports at most 5.1, so e.g. 7.1 TrueHD is downmixed
+        AudioCodec::Ac3 => ("ac3", options.audio_channels.unwrap_or(6).min(6), "640k"),
+    };
+
+    let channels = channels.to_string();
+
+    let encoding = [
+        "-c:a",    // encode audio with
+        codec,     // the selected codec
+        "-ac",     // set audio channels to
+        &channels, // stereo or up to 5.1
+        "-b:a",    // set audio bitrate to
+        bitrate,   // 192 or 640 kbit/s
+    ];
+
+    encoding.iter().map(OsString::from).collect()
+}
+
+fn input_args(path: &Path, options: &Options) -> Vec<OsString> {
+    let mut args = Vec::new();
+
+    if let Some(start) = options.start {
+        // input seeking is fast, and accurate when transcoding.
+        // when copying, it starts at the keyframe before `start`.
+        args.push("-ss".into());
+        args.push(format!("{:.3}", start).into());
+    }
+
+    args.push("-i".into());
+    args.push(path.into());
+
+    let filters = video_filters(path, options);
+
+    if filters.is_empty() {
+        // include the first video stream
+        args.push("-map".into());
+        args.push("0:v:0".into());
+    } else {
+        // include the first video stream after filtering it
+        args.push("-filter_complex".into());
+        args.push(filter_graph(&filters).into());
+        args.push("-map".into());
+        args.push("[v]".into());
+    }
+
+    // include the selected audio stream, or the first one if any
+    args.push("-map".into());
+
+    match options.audio {
+        Some(index) => args.push(format!("0:{}", index).into()),
+        None => args.push("0:a:0?".into()),
+    }
+
+    args
+}
+
+/// Converts HDR10 and HLG video to SDR on the CPU, since the colors
+/// are washed out when the receiver displays it as is.
+const TONE_MAP_FILTERS: [&str; 6] = [
+    "zscale=transfer=linear:npl=100", // linearize the HDR transfer
+    "format=gbrpf32le",               // in floating point
+    "zscale=primaries=bt709",         // convert BT.2020 colors to BT.709
+    "tonemap=tonemap=hable:desat=0",  // compress the brightness range
+    "zscale=transfer=bt709:matrix=bt709:range=tv", // apply the SDR transfer
+    "format=yuv420p",                 // back to 8 bit 4:2:0
+];
+
+/// Filters applied to the first video stream in order. These are only
+/// set in the options when transcoding.
+fn video_filters(path: &Path, options: &Options) -> Vec<String> {
+    let mut filters = Vec::new();
+
+    if options.tone_map {
+        // subtitles are SDR, so the video is converted before drawing them
+        filters.extend(TONE_MAP_FILTERS.iter().map(|filter| filter.to_string()));
+    }
+
+    if let Some(burn_in) = options.burn_in {
+        filters.push(burn_in::filter(path, burn_in, options.start));
+    }
+
+    if let Some((width, height)) = options.max_size {
+        // fit into the size without upscaling or changing the aspect ratio
+        filters.push(format!(
+            "scale=w=min(iw\\,{}):h=min(ih\\,{}):force_original_aspect_ratio=decrease:force_divisible_by=2",
+            width, height
+        ));
+    }
+
+    filters
+}
+
+/// Chains `filters` on the first video stream into a graph with the
+/// output `[v]`. Filters starting with an input label, e.g. `[0:3]overlay`,
+/// take the output of the chain so far as their first input.
+fn filter_graph(filters: &[String]) -> String {
+    let mut graph = String::from("[0:v:0]");
+
+    for (i, filter) in filters.iter().enumerate() {
+        if i > 0 {
+            if filter.starts_with('[') {
+                graph.push_str(&format!("[v{}];[v{}]", i, i));
+            } else {
+                graph.push(',');
+            }
+        }
+
+        graph.push_str(filter);
+    }
+
+    graph.push_str("[v]");
+    graph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(&["[0:3]overlay"] => "[0:v:0][0:3]overlay[v]"; "when overlay is first")]
+    #[test_case(&["a", "b"] => "[0:v:0]a,b[v]"; "when chained")]
+    #[test_case(&["a", "[0:3]overlay", "b"] => "[0:v:0]a[v1];[v1][0:3]overlay,b[v]"; "when overlay is in between")]
+    fn builds_filter_graph(filters: &[&str]) -> String {
+        let filters: Vec<String> = filters.iter().map(|f| f.to_string()).collect();
+        filter_graph(&filters)
+    }
+
+    #[test]
+    fn scales_after_burning_in() {
+        let options = Options {
+            burn_in: Some(BurnIn::Image { index: 3 }),
+            max_size: Some((1920, 1080)),
+            ..Default::default()
+        };
+
+        let filters = video_filters(Path::new("a.mkv"), &options);
+        assert_eq!(filters.len(), 2);
+        assert_eq!(filters[0], "[0:3]overlay");
+        assert!(filters[1].starts_with("scale=w=min(iw\\,1920):h=min(ih\\,1080):"));
+    }
+
+    #[test]
+    fn tone_maps_before_burning_in() {
+        let options = Options {
+            burn_in: Some(BurnIn::Image { index: 3 }),
+            tone_map: true,
+            ..Default::default()
+        };
+
+        let graph = filter_graph(&video_filters(Path::new("a.mkv"), &options));
+        assert!(graph.starts_with("[0:v:0]zscale=transfer=linear:npl=100,"));
+        assert!(graph.ends_with(",format=yuv420p[v6];[v6][0:3]overlay[v]"));
+    }
+}
@@ -1,115 +1,333 @@
 // https://docs.rs/crate/actix-files/0.5.0/source/src/named.rs with modifications
-use super::range::HttpRange;
+//! The file is opened, inspected and seeked asynchronously in the handler,
+//! so slow disks such as NAS mounts don't stall other requests, and the
+//! responder only builds the response. sendfile/splice can't be used:
+//! Rocket hands the body to hyper as a stream of buffers and never exposes
+//! the socket, which is a TLS stream on the HTTPS listener anyway. Reading
+//! large chunks at a time gets close to the throughput of the disk instead.
+use super::{
+    conditional::Validators,
+    jobs::JobQueue,
+    multipart::{self, MultipartRanges},
+    range::HttpRange,
+    traffic::{Meter, Traffic},
+};
+use crate::{
+    app_result::AppResult,
+    auth::MediaAccess,
+    library::Library,
+    media::MediaRegistry,
+    profile::ProfileConfig,
+    sniff::{Container, SNIFF_LEN},
+};
+use futures::{ready, Future};
 use log::{debug, error, info, warn};
 use rocket::{
     async_trait, get,
     http::{ContentType, Header, Status},
     request::{FromRequest, Outcome},
     response::{Responder, Result as RocketResult},
-    Response,
+    Request, Response, State,
 };
+use serde::Deserialize;
 use std::{
-    fs::File,
-    io::{Result as IoResult, Seek, SeekFrom},
+    convert::Infallible,
+    io::{Result as IoResult, SeekFrom},
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
     pin::Pin,
     task::{Context, Poll},
 };
 use thiserror::Error;
 use tokio::{
     fs::File as AsyncFile,
-    io::{AsyncRead, AsyncSeek, ReadBuf},
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf},
+    time::Sleep,
 };
 
-#[get("/video/<path>")]
-pub(crate) async fn handler<'r>(path: &str, range: Range) -> VideoResponder {
-    let path: PathBuf = path.into();
-    VideoResponder { path, range }
+/// Rocket reads 4 KiB at a time by default, which is slow for large files.
+const DEFAULT_READ_AHEAD: usize = 256 * 1024;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct VideoConfig {
+    /// How many bytes are read from the file at a time when streaming.
+    #[serde(default = "default_read_ahead")]
+    pub(crate) read_ahead: usize,
+}
+
+fn default_read_ahead() -> usize {
+    DEFAULT_READ_AHEAD
+}
+
+/// Streams the video with the media `id`. Without a Range header the whole
+/// file is returned with 200 OK, ranges that select none of it are answered
+/// with 416 Range Not Satisfiable. HEAD requests are answered by Rocket through
+/// this route with the same headers and no body. If the video has been
+/// transcoded by a job, the transcoded file is streamed instead.
+#[get("/video/<id>?<profile>&<device>")]
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn handler(
+    _access: MediaAccess,
+    id: &str,
+    profile: Option<&str>,
+    device: Option<&str>,
+    range: Option<Range>,
+    preconditions: Preconditions,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    jobs: &State<JobQueue>,
+    profiles: &State<ProfileConfig>,
+    config: &State<VideoConfig>,
+) -> Result<Option<VideoResponder>, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(None),
+    };
+
+    // only the receivers the file was transcoded for can play it
+    let profile = profiles.resolve(profile, device);
+
+    let path = match jobs.output(&path, &profile).await {
+        Some(output) => {
+            info!(
+                "streaming transcoded {} for {}",
+                output.display(),
+                path.display()
+            );
+            output
+        }
+        None => path,
+    };
+
+    let range = range.as_ref().map(|range| range.as_str());
+    let responder =
+        VideoResponder::open(&path, range, &preconditions, config.read_ahead.max(1)).await;
+    Ok(Some(responder))
+}
+
+/// The conditional request headers, see `Validators`.
+pub(crate) struct Preconditions {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+    if_range: Option<String>,
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Preconditions {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let header = |name| headers.get_one(name).map(|s| s.to_owned());
+
+        Outcome::Success(Preconditions {
+            if_none_match: header("If-None-Match"),
+            if_modified_since: header("If-Modified-Since"),
+            if_range: header("If-Range"),
+        })
+    }
 }
 
+type Body = Box<dyn AsyncRead + Send + Unpin>;
+
 pub(crate) struct VideoResponder {
     path: PathBuf,
-    range: Range,
+    status: Status,
+    headers: Vec<Header<'static>>,
+    body: Option<(u64, Body)>,
+    read_ahead: usize,
 }
 
-impl<'r> Responder<'r, 'r> for VideoResponder {
-    fn respond_to(self, _request: &'r rocket::Request<'_>) -> RocketResult<'r> {
-        let path = self.path;
-        let mut response = Response::build();
-        response.header(Header::new("Accept-Ranges", "bytes"));
+impl VideoResponder {
+    async fn open(
+        path: &Path,
+        range: Option<&str>,
+        preconditions: &Preconditions,
+        read_ahead: usize,
+    ) -> Self {
+        let mut response = Self {
+            path: path.to_path_buf(),
+            status: Status::Ok,
+            headers: vec![Header::new("Accept-Ranges", "bytes")],
+            body: None,
+            read_ahead,
+        };
+
+        let mut file = match AsyncFile::open(path).await {
+            Ok(file) => file,
+            Err(_) => return response.with_status(Status::NotFound),
+        };
+
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+
+        let content_type = match (&mut file)
+            .take(SNIFF_LEN as u64)
+            .read_to_end(&mut head)
+            .await
+        {
+            Ok(_) => content_type(path, &head),
+            Err(err) => {
+                error!("failed to read file {}: {}", path.display(), err);
+                return response.with_status(Status::InternalServerError);
+            }
+        };
+
+        let (size, modified) = match file.metadata().await {
+            Ok(metadata) => (metadata.len(), metadata.modified().ok()),
+            Err(err) => {
+                let path = path.display();
+                error!("failed to get metadata for file {}: {}", path, err);
+                return response.with_status(Status::InternalServerError);
+            }
+        };
 
-        if let Ok(mut file) = File::open(&path) {
-            if let Some(ext) = path.extension() {
-                let ext_str = ext.to_string_lossy();
+        let validators = Validators::new(size, modified);
+        response.header(Header::new("ETag", validators.etag().to_owned()));
 
-                if let Some(content_type) = ContentType::from_extension(&ext_str) {
-                    response.header(content_type);
-                } else {
-                    warn!("no content type found for {} extension", ext_str);
-                }
-            } else {
-                warn!("file {} does not have an extension", path.display());
-            }
+        if let Some(last_modified) = validators.last_modified() {
+            response.header(Header::new("Last-Modified", last_modified));
+        }
 
-            let size = match file.metadata() {
-                Ok(metadata) => metadata.len(),
-                Err(err) => {
-                    let path = path.display();
-                    error!("failed to get metadata for file {}: {}", path, err);
-                    response.status(Status::InternalServerError);
-                    return Ok(response.finalize());
-                }
-            };
+        let if_none_match = preconditions.if_none_match.as_deref();
+        let if_modified_since = preconditions.if_modified_since.as_deref();
 
-            let mut length = size;
-            let mut offset = 0;
+        if validators.not_modified(if_none_match, if_modified_since) {
+            info!("file {} not modified", path.display());
+            return response.with_status(Status::NotModified);
+        }
 
-            info!("range: {}", *self.range);
+        let range = range.filter(|_| {
+            let applies = validators.range_applies(preconditions.if_range.as_deref());
 
-            match HttpRange::parse(&*self.range, length) {
-                Ok(ranges) => {
-                    length = ranges[0].length;
-                    offset = ranges[0].start;
+            if !applies {
+                info!("if-range validator is stale, sending entire file");
+            }
 
-                    response.header(Header::new("Content-Encoding", "identity"));
-                    response.header(Header::new(
-                        "Content-Range",
-                        format!("bytes {}-{}/{}", offset, offset + length - 1, size),
-                    ));
-                }
+            applies
+        });
+
+        let ranges = if let Some(range) = range {
+            info!("range: {}", range);
+
+            let ranges = match HttpRange::parse(range, size) {
+                Ok(ranges) => HttpRange::merge(ranges),
                 Err(err) => {
                     warn!("range parsing error: {}", err);
-                    response.header(Header::new("Content-Range", format!("bytes */{}", length)));
-                    response.status(Status::RangeNotSatisfiable);
+                    Vec::new()
                 }
-            }
+            };
 
-            if offset > 0 {
-                if let Err(err) = file.seek(SeekFrom::Start(offset)) {
-                    error!("failed to seek in file {}: {}", path.display(), err);
-                    response.status(Status::InternalServerError);
-                    return Ok(response.finalize());
-                }
+            // e.g. `bytes=-0` or any range of an empty file
+            if ranges.is_empty() {
+                let content_range = format!("bytes */{}", size);
+                response.header(Header::new("Content-Range", content_range));
+                return response.with_status(Status::RangeNotSatisfiable);
             }
 
-            if offset > 0 || length < size {
-                response.status(Status::PartialContent);
-            } else {
-                response.status(Status::Ok);
+            ranges
+        } else {
+            info!("no range requested, sending entire file");
+            Vec::new()
+        };
+
+        if ranges.len() > 1 {
+            let boundary = multipart::boundary();
+            let body = MultipartRanges::new(file, &ranges, size, content_type.as_ref(), &boundary);
+
+            debug!("size {} ranges {:?} len {}", size, ranges, body.len());
+
+            response.header(multipart::content_type(&boundary).into());
+            response.body = Some((body.len(), Box::new(body)));
+            return response.with_status(Status::PartialContent);
+        }
+
+        if let Some(content_type) = content_type {
+            response.header(content_type.into());
+        }
+
+        let (offset, length) = match ranges.first() {
+            Some(range) => {
+                response.header(Header::new("Content-Encoding", "identity"));
+                response.header(Header::new(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", range.start, range.end(), size),
+                ));
+
+                (range.start, range.length)
             }
+            None => (0, size),
+        };
 
-            debug!("size {} len {} offset {}", size, length, offset);
+        // the start was read to detect the content type
+        if let Err(err) = file.seek(SeekFrom::Start(offset)).await {
+            error!("failed to seek in file {}: {}", path.display(), err);
+            return response.with_status(Status::InternalServerError);
+        }
+
+        debug!("size {} len {} offset {}", size, length, offset);
+
+        response.body = Some((length, Box::new(file.take(length))));
 
-            response.sized_body(Some(length as usize), FileWrapper::from(file));
+        if offset > 0 || length < size {
+            response.with_status(Status::PartialContent)
         } else {
-            response.status(Status::NotFound);
+            response
+        }
+    }
+
+    fn header(&mut self, header: Header<'static>) {
+        self.headers.push(header);
+    }
+
+    fn with_status(mut self, status: Status) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for VideoResponder {
+    fn respond_to(self, request: &'r Request<'_>) -> RocketResult<'static> {
+        let mut response = Response::build();
+        response.status(self.status);
+
+        for header in self.headers {
+            response.header(header);
+        }
+
+        if let Some((length, body)) = self.body {
+            // the body is streamed, so Rocket doesn't know its length
+            response.header(Header::new("Content-Length", length.to_string()));
+            response.max_chunk_size(self.read_ahead);
+            response.streamed_body(FileWrapper::new(body).metered(request, &self.path));
         }
 
         Ok(response.finalize())
     }
 }
 
+/// Detects the content type from the first bytes of the file, falling
+/// back to the extension for containers that aren't detected.
+fn content_type(path: &Path, head: &[u8]) -> Option<ContentType> {
+    if let Some(container) = Container::detect(head) {
+        debug!("detected {:?} in {}", container, path.display());
+        return Some(container.content_type());
+    }
+
+    if let Some(ext) = path.extension() {
+        let ext_str = ext.to_string_lossy();
+        let content_type = ContentType::from_extension(&ext_str);
+
+        if content_type.is_none() {
+            warn!("no content type found for {} extension", ext_str);
+        }
+
+        content_type
+    } else {
+        warn!("file {} does not have an extension", path.display());
+        None
+    }
+}
+
 pub(crate) struct Range(String);
 
 impl Deref for Range {
@@ -145,33 +363,91 @@ impl<'a, 'r> FromRequest<'r> for Range {
 #[error("Range header is missing")]
 pub(crate) struct MissingRangeHeaderError;
 
-// Only for resetting system idle timer on Drop
-// when the request has streamed what it needs to from the file.
-struct FileWrapper {
-    file: Pin<Box<AsyncFile>>,
+// For resetting system idle timer on Drop when the request has streamed
+// what it needs to from the file, and for counting and limiting traffic.
+pub(super) struct FileWrapper<R> {
+    file: Pin<Box<R>>,
+    meter: Option<Meter>,
+    sleep: Option<Pin<Box<Sleep>>>,
 }
 
-impl From<File> for FileWrapper {
-    fn from(file: File) -> Self {
+impl<R> FileWrapper<R> {
+    pub(super) fn new(file: R) -> Self {
         stop_system_idle_timer();
 
         Self {
-            file: Box::pin(AsyncFile::from_std(file)),
+            file: Box::pin(file),
+            meter: None,
+            sleep: None,
         }
     }
+
+    /// Counts the bytes read in `/streams` and applies the bandwidth
+    /// limits if Rocket manages the traffic of `request`.
+    pub(super) fn metered(mut self, request: &Request<'_>, file: &Path) -> Self {
+        if let Some(traffic) = request.rocket().state::<Traffic>() {
+            let file = file.display().to_string();
+            self.meter = Some(traffic.meter(request.client_ip(), file));
+        }
+
+        self
+    }
 }
 
-impl AsyncRead for FileWrapper {
+impl<R: AsyncRead> AsyncRead for FileWrapper<R> {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<IoResult<()>> {
-        self.get_mut().file.as_mut().poll_read(cx, buf)
+        let this = self.get_mut();
+
+        let meter = match &mut this.meter {
+            Some(meter) => meter,
+            None => return this.file.as_mut().poll_read(cx, buf),
+        };
+
+        if !meter.is_limited() {
+            let filled = buf.filled().len();
+            ready!(this.file.as_mut().poll_read(cx, buf))?;
+            meter.record(buf.filled().len() - filled);
+            return Poll::Ready(Ok(()));
+        }
+
+        if let Some(sleep) = &mut this.sleep {
+            ready!(sleep.as_mut().poll(cx));
+            this.sleep = None;
+        }
+
+        let allowed = match meter.allowance(buf.remaining()) {
+            Ok(allowed) => allowed,
+            Err(wait) => {
+                let mut sleep = Box::pin(tokio::time::sleep(wait));
+
+                if sleep.as_mut().poll(cx).is_pending() {
+                    this.sleep = Some(sleep);
+                } else {
+                    // the wait was too short to sleep, so ask again right away
+                    cx.waker().wake_by_ref();
+                }
+
+                return Poll::Pending;
+            }
+        };
+
+        // read at most the allowed bytes, into a buffer since `buf` can't be shrunk
+        meter.scratch.resize(allowed, 0);
+        let mut scratch = ReadBuf::new(&mut meter.scratch);
+        ready!(this.file.as_mut().poll_read(cx, &mut scratch))?;
+        let read = scratch.filled();
+        buf.put_slice(read);
+        let read = read.len();
+        meter.record(read);
+        Poll::Ready(Ok(()))
     }
 }
 
-impl AsyncSeek for FileWrapper {
+impl<R: AsyncSeek> AsyncSeek for FileWrapper<R> {
     fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> IoResult<()> {
         self.get_mut().file.as_mut().start_seek(position)
     }
@@ -181,7 +457,7 @@ impl AsyncSeek for FileWrapper {
     }
 }
 
-impl Drop for FileWrapper {
+impl<R> Drop for FileWrapper<R> {
     fn drop(&mut self) {
         start_system_idle_timer();
     }
@@ -219,3 +495,307 @@ fn stop_system_idle_timer() {}
 
 #[cfg(not(target_os = "windows"))]
 fn start_system_idle_timer() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::Auth, chromecast::traffic::TrafficConfig};
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+    use rocket::{
+        config::LogLevel,
+        local::asynchronous::{Client, LocalRequest},
+        routes, Config,
+    };
+    use std::{
+        env,
+        net::SocketAddr,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    async fn client(name: &str, read_ahead: usize) -> Client {
+        client_with_traffic(name, read_ahead, &TrafficConfig::default()).await
+    }
+
+    async fn client_with_traffic(name: &str, read_ahead: usize, traffic: &TrafficConfig) -> Client {
+        let root = env::temp_dir().join(format!("videocaster-test-{}", name));
+
+        let config = Config {
+            log_level: LogLevel::Off,
+            ..Config::debug_default()
+        };
+
+        let rocket = rocket::custom(config)
+            .mount("/", routes![handler, blocking_handler])
+            .manage(Auth::new())
+            .manage(Library::new(&[library_root()]))
+            .manage(MediaRegistry::default())
+            .manage(JobQueue::new(root, 1))
+            .manage(ProfileConfig::default())
+            .manage(VideoConfig { read_ahead })
+            .manage(Traffic::new(traffic));
+
+        Client::untracked(rocket).await.expect("rocket")
+    }
+
+    fn library_root() -> PathBuf {
+        env::temp_dir().join("videocaster-videos")
+    }
+
+    async fn create_file(name: &str, size: usize) -> PathBuf {
+        let root = library_root();
+        tokio::fs::create_dir_all(&root).await.unwrap();
+        let path = root.join(name);
+        let bytes: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&path, &bytes).await.unwrap();
+        path
+    }
+
+    /// Requests `uri` from the browser on this machine, which is trusted.
+    fn get<'c>(client: &'c Client, uri: &str) -> LocalRequest<'c> {
+        let local: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        client.get(uri.to_owned()).remote(local)
+    }
+
+    fn uri(client: &Client, path: &Path) -> String {
+        let media = client.rocket().state::<MediaRegistry>().expect("media");
+        format!("/video/{}", media.register(path))
+    }
+
+    #[tokio::test]
+    async fn streams_only_the_requested_range() {
+        let path = create_file("videocaster-range.mp4", 100_000).await;
+        let client = client("range", 1024).await;
+        let uri = uri(&client, &path);
+
+        let response = get(&client, &uri)
+            .header(Header::new("Range", "bytes=1000-1999"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::PartialContent);
+        let content_length = response.headers().get_one("Content-Length");
+        assert_eq!(content_length, Some("1000"));
+        let body = response.into_bytes().await.unwrap();
+        let expected: Vec<u8> = (1000..2000).map(|i| (i % 251) as u8).collect();
+        assert_eq!(body, expected);
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_entire_file_without_range() {
+        let path = create_file("videocaster-full.mp4", 100_000).await;
+        let client = client("full", 1024).await;
+        let uri = uri(&client, &path);
+        let response = get(&client, &uri).dispatch().await;
+
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::MP4));
+        assert_eq!(response.into_bytes().await.map(|b| b.len()), Some(100_000));
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_multiple_ranges() {
+        let path = create_file("videocaster-multi.mp4", 100).await;
+        let client = client("multi", 1024).await;
+        let uri = uri(&client, &path);
+
+        let response = get(&client, &uri)
+            .header(Header::new("Range", "bytes=20-29,0-4,3-9"))
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::PartialContent);
+        let content_type = response.content_type().expect("content type");
+        assert_eq!(content_type.media_type().sub(), "byteranges");
+        let boundary = content_type.param("boundary").expect("boundary").to_owned();
+        let content_length = response
+            .headers()
+            .get_one("Content-Length")
+            .map(str::to_owned);
+
+        let body = response.into_bytes().await.unwrap();
+        let mut expected = format!(
+            "--{0}\r\nContent-Type: video/mp4\r\nContent-Range: bytes 0-9/100\r\n\r\n",
+            boundary
+        )
+        .into_bytes();
+        expected.extend(0..10);
+        expected.extend_from_slice(
+            format!(
+                "\r\n--{0}\r\nContent-Type: video/mp4\r\nContent-Range: bytes 20-29/100\r\n\r\n",
+                boundary
+            )
+            .as_bytes(),
+        );
+        expected.extend(20..30);
+        expected.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        assert_eq!(body, expected);
+        assert_eq!(content_length, Some(expected.len().to_string()));
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_unsatisfiable_ranges() {
+        let cases = [
+            ("videocaster-zero.mp4", 1000, "bytes=-0"),
+            ("videocaster-past.mp4", 1000, "bytes=1000-"),
+            ("videocaster-empty.mp4", 0, "bytes=0-"),
+        ];
+
+        let client = client("unsatisfiable", 1024).await;
+
+        for (name, size, range) in cases {
+            let path = create_file(name, size).await;
+            let uri = uri(&client, &path);
+
+            let response = get(&client, &uri)
+                .header(Header::new("Range", range))
+                .dispatch()
+                .await;
+
+            assert_eq!(response.status(), Status::RangeNotSatisfiable, "{}", range);
+            let content_range = response.headers().get_one("Content-Range");
+            assert_eq!(content_range, Some(format!("bytes */{}", size).as_str()));
+
+            tokio::fs::remove_file(path).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_paths_without_an_id() {
+        let path = create_file("videocaster-unlisted.mp4", 1000).await;
+        let client = client("unlisted", 1024).await;
+        let uri = format!(
+            "/video/{}",
+            utf8_percent_encode(&path.to_string_lossy(), NON_ALPHANUMERIC)
+        );
+
+        let response = get(&client, &uri).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_files_outside_the_library() {
+        let path = env::temp_dir().join("videocaster-outside.mp4");
+        tokio::fs::write(&path, b"secret").await.unwrap();
+        let client = client("outside", 1024).await;
+        let uri = uri(&client, &path);
+
+        let response = get(&client, &uri).dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let body = response.into_string().await.unwrap();
+        assert!(body.contains(r#""success":false"#));
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn requires_token_from_other_devices() {
+        let path = create_file("videocaster-token.mp4", 1000).await;
+        let client = client("token", 1024).await;
+        let uri = uri(&client, &path);
+        let id = uri.trim_start_matches("/video/");
+        let receiver: SocketAddr = "192.168.1.20:40000".parse().unwrap();
+
+        let response = client.get(uri.as_str()).remote(receiver).dispatch().await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let auth = client.rocket().state::<Auth>().expect("auth");
+        let token = auth.media_token(id, SystemTime::now());
+        let uri = format!("{}?token={}", uri, token);
+        let response = client.get(uri).remote(receiver).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_bandwidth() {
+        let path = create_file("videocaster-limit.mp4", 500_000).await;
+
+        let traffic = TrafficConfig {
+            max_stream_mbps: Some(8.0),
+            max_total_mbps: None,
+        };
+
+        // 1 MB/s with a burst of 250 KB
+        let client = client_with_traffic("limit", 64 * 1024, &traffic).await;
+        let uri = uri(&client, &path);
+        let start = Instant::now();
+        let response = get(&client, &uri).dispatch().await;
+        assert_eq!(response.into_bytes().await.map(|b| b.len()), Some(500_000));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+
+    /// The handler before files were opened asynchronously, which opened
+    /// the file with `std::fs` in the responder and let Rocket read it in
+    /// its default chunks. Only kept to compare the throughput with.
+    #[get("/blocking/<id>")]
+    async fn blocking_handler(id: &str, media: &State<MediaRegistry>) -> Option<BlockingResponder> {
+        media.path(id).map(BlockingResponder)
+    }
+
+    struct BlockingResponder(PathBuf);
+
+    impl<'r> Responder<'r, 'static> for BlockingResponder {
+        fn respond_to(self, _request: &'r Request<'_>) -> RocketResult<'static> {
+            let file = std::fs::File::open(&self.0).map_err(|_| Status::NotFound)?;
+            let metadata = file.metadata().map_err(|_| Status::InternalServerError)?;
+
+            Response::build()
+                .header(Header::new("Accept-Ranges", "bytes"))
+                .sized_body(
+                    Some(metadata.len() as usize),
+                    FileWrapper::new(tokio::fs::File::from_std(file)),
+                )
+                .ok()
+        }
+    }
+
+    /// Compares the throughput with the blocking handler and with Rocket's
+    /// default chunk size, which was used before the read-ahead was
+    /// configurable. Run with
+    /// `cargo test --release -- --ignored --nocapture compares_throughput`.
+    #[tokio::test]
+    #[ignore]
+    async fn compares_throughput() {
+        const SIZE: usize = 256 * 1024 * 1024;
+        let path = create_file("videocaster-bench.mp4", SIZE).await;
+
+        let measure = |name: String, uri: String, client: Client| async move {
+            let start = Instant::now();
+            let response = get(&client, &uri).dispatch().await;
+            let body = response.into_bytes().await.unwrap();
+            let elapsed = start.elapsed().as_secs_f64();
+            assert_eq!(body.len(), SIZE);
+
+            println!(
+                "{:>26}: {:>8.1} MiB/s",
+                name,
+                SIZE as f64 / elapsed / 1024.0 / 1024.0
+            );
+        };
+
+        let blocking = client("bench", DEFAULT_READ_AHEAD).await;
+        let blocking_uri = uri(&blocking, &path).replace("/video/", "/blocking/");
+        measure("blocking std::fs".to_owned(), blocking_uri, blocking).await;
+
+        for read_ahead in [4096, 64 * 1024, DEFAULT_READ_AHEAD, 1024 * 1024] {
+            let client = client("bench", read_ahead).await;
+            let uri = uri(&client, &path);
+            let name = format!("read ahead {} bytes", read_ahead);
+            measure(name, uri, client).await;
+        }
+
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+//! Subcommands let scripts use what the server does without the UI, e.g.
+//! `videocaster subs search movie.mkv`. Without a subcommand the server is
+//! started, so launching the app the usual way still works.
+use crate::{
+    frame, opensubs, probe, profile::ProfileConfig, settings::Settings, subtitles::by_path,
+};
+use anyhow::{bail, Context, Result};
+use rocket::figment::Figment;
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+pub(crate) const USAGE: &str = "\
+usage: videocaster [serve] [--no-browser]
+       videocaster probe <file>
+       videocaster subs search <file>
+       videocaster subs convert <in.srt> <out.vtt>
+       videocaster thumbnail <file> [<out.jpg>]";
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Command {
+    /// Starts the server and opens the UI unless `no_browser` is set.
+    Serve {
+        no_browser: bool,
+    },
+
+    /// Prints the streams of a file and how the default receiver plays it.
+    Probe {
+        file: PathBuf,
+    },
+
+    /// Prints the subtitles found for a file by its hash.
+    SearchSubs {
+        file: PathBuf,
+    },
+
+    /// Converts SRT subtitles to WebVTT, which is what receivers show.
+    ConvertSubs {
+        input: PathBuf,
+        output: PathBuf,
+    },
+
+    /// Writes a frame of a video as a JPEG, named after the video by default.
+    Thumbnail {
+        file: PathBuf,
+        output: Option<PathBuf>,
+    },
+
+    Help,
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub(crate) enum CliError {
+    #[error("unknown command {0:?}")]
+    UnknownCommand(String),
+
+    #[error("missing {0}")]
+    Missing(&'static str),
+
+    #[error("unexpected argument {0:?}")]
+    Unexpected(String),
+}
+
+/// Parses the arguments after the program name.
+pub(crate) fn parse<I: IntoIterator<Item = OsString>>(args: I) -> Result<Command, CliError> {
+    let mut args = Args(args.into_iter().collect());
+
+    let command = match args.peek().as_deref() {
+        None | Some("--no-browser") => return serve(args),
+        Some(command) => command.to_owned(),
+    };
+
+    args.0.pop_front();
+
+    let command = match command.as_str() {
+        "serve" => return serve(args),
+        "probe" => Command::Probe {
+            file: args.required("file")?,
+        },
+        "subs" => match args.0.pop_front().map(lossy).as_deref() {
+            Some("search") => Command::SearchSubs {
+                file: args.required("file")?,
+            },
+            Some("convert") => Command::ConvertSubs {
+                input: args.required("input file")?,
+                output: args.required("output file")?,
+            },
+            Some(other) => return Err(CliError::UnknownCommand(format!("subs {}", other))),
+            None => return Err(CliError::Missing("subs command")),
+        },
+        "thumbnail" => Command::Thumbnail {
+            file: args.required("file")?,
+            output: args.0.pop_front().map(PathBuf::from),
+        },
+        "help" | "--help" | "-h" => Command::Help,
+        other => return Err(CliError::UnknownCommand(other.to_owned())),
+    };
+
+    args.finish()?;
+    Ok(command)
+}
+
+fn serve(mut args: Args) -> Result<Command, CliError> {
+    let mut no_browser = false;
+
+    while let Some(arg) = args.0.pop_front() {
+        match lossy(arg).as_str() {
+            "--no-browser" => no_browser = true,
+            other => return Err(CliError::Unexpected(other.to_owned())),
+        }
+    }
+
+    Ok(Command::Serve { no_browser })
+}
+
+struct Args(VecDeque<OsString>);
+
+impl Args {
+    fn peek(&self) -> Option<String> {
+        self.0.front().cloned().map(lossy)
+    }
+
+    fn required(&mut self, name: &'static str) -> Result<PathBuf, CliError> {
+        self.0
+            .pop_front()
+            .map(PathBuf::from)
+            .ok_or(CliError::Missing(name))
+    }
+
+    fn finish(mut self) -> Result<(), CliError> {
+        match self.0.pop_front() {
+            Some(arg) => Err(CliError::Unexpected(lossy(arg))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn lossy(arg: OsString) -> String {
+    arg.to_string_lossy().into_owned()
+}
+
+/// Runs a command other than `Serve`, the `settings` must be applied.
+pub(crate) async fn run(command: Command, settings: &Settings, figment: &Figment) -> Result<()> {
+    match command {
+        Command::Serve { .. } => bail!("the server is started from main"),
+        Command::Probe { file } => {
+            let profiles = figment.extract::<ProfileConfig>()?;
+            let mut info = probe::probe(&file).await?;
+            info.verdict = profiles
+                .resolve(None, None)
+                .verdict(&info, info.audio.first());
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        }
+        Command::SearchSubs { file } => {
+            for subtitle in by_path::search(&file, &settings.subtitle_languages).await? {
+                println!("{}\t{}", subtitle.name, subtitle.url);
+            }
+        }
+        Command::ConvertSubs { input, output } => {
+            let srt = tokio::fs::read(&input)
+                .await
+                .with_context(|| format!("failed to read {}", input.display()))?;
+
+            let vtt = opensubs::srt_to_vtt(&String::from_utf8_lossy(&srt));
+            tokio::fs::write(&output, vtt)
+                .await
+                .with_context(|| format!("failed to write {}", output.display()))?;
+        }
+        Command::Thumbnail { file, output } => {
+            let image = frame::extract_jpeg(&file).await?;
+
+            if image.is_empty() {
+                bail!("{} is too short for a thumbnail", file.display());
+            }
+
+            let output = output.unwrap_or_else(|| thumbnail_path(&file));
+            tokio::fs::write(&output, image)
+                .await
+                .with_context(|| format!("failed to write {}", output.display()))?;
+            println!("{}", output.display());
+        }
+        Command::Help => println!("{}", USAGE),
+    }
+
+    Ok(())
+}
+
+/// `movie.2020.mkv` becomes `movie.2020.jpg` in the current directory.
+fn thumbnail_path(file: &Path) -> PathBuf {
+    let mut name = file.file_stem().unwrap_or_default().to_os_string();
+    name.push(".jpg");
+    PathBuf::from(name)
+}
+
+/// Release builds on Windows have no console of their own, so output is
+/// written to the one the command was run from, if any.
+#[cfg(all(target_os = "windows", not(debug_assertions)))]
+pub(crate) fn attach_console() {
+    use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(all(target_os = "windows", not(debug_assertions))))]
+pub(crate) fn attach_console() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn parse_str(args: &[&str]) -> Result<Command, CliError> {
+        parse(args.iter().map(OsString::from))
+    }
+
+    #[test_case(&[] => Ok(Command::Serve { no_browser: false }); "without a command")]
+    #[test_case(&["--no-browser"] => Ok(Command::Serve { no_browser: true }); "with only flags")]
+    #[test_case(&["serve", "--no-browser"] => Ok(Command::Serve { no_browser: true }); "serve")]
+    #[test_case(&["probe", "a.mkv"] => Ok(Command::Probe { file: "a.mkv".into() }); "probe")]
+    #[test_case(&["subs", "search", "a.mkv"] => Ok(Command::SearchSubs { file: "a.mkv".into() }); "subs search")]
+    #[test_case(&["subs", "convert", "a.srt", "a.vtt"] => Ok(Command::ConvertSubs { input: "a.srt".into(), output: "a.vtt".into() }); "subs convert")]
+    #[test_case(&["thumbnail", "a.mkv"] => Ok(Command::Thumbnail { file: "a.mkv".into(), output: None }); "thumbnail")]
+    #[test_case(&["thumbnail", "a.mkv", "b.jpg"] => Ok(Command::Thumbnail { file: "a.mkv".into(), output: Some("b.jpg".into()) }); "thumbnail with output")]
+    #[test_case(&["--help"] => Ok(Command::Help); "help")]
+    fn parses_commands(args: &[&str]) -> Result<Command, CliError> {
+        parse_str(args)
+    }
+
+    #[test_case(&["play"] => CliError::UnknownCommand("play".to_owned()); "unknown command")]
+    #[test_case(&["subs", "list"] => CliError::UnknownCommand("subs list".to_owned()); "unknown subs command")]
+    #[test_case(&["subs"] => CliError::Missing("subs command"); "missing subs command")]
+    #[test_case(&["subs", "convert", "a.srt"] => CliError::Missing("output file"); "missing output")]
+    #[test_case(&["probe", "a.mkv", "b.mkv"] => CliError::Unexpected("b.mkv".to_owned()); "extra argument")]
+    #[test_case(&["serve", "--verbose"] => CliError::Unexpected("--verbose".to_owned()); "unknown flag")]
+    fn rejects_invalid_arguments(args: &[&str]) -> CliError {
+        parse_str(args).unwrap_err()
+    }
+
+    #[test]
+    fn names_thumbnails_after_the_video() {
+        assert_eq!(
+            thumbnail_path(Path::new("/videos/movie.2020.mkv")),
+            PathBuf::from("movie.2020.jpg")
+        );
+    }
+}
@@ -0,0 +1,292 @@
+//! This module contains functions for locating and running ffmpeg
+//! which is used for extracting frames and transcoding videos.
+use anyhow::{anyhow, Context as _, Error};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use std::{
+    env::consts::EXE_SUFFIX,
+    ffi::OsString,
+    io::Result as IoResult,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::Stdio,
+    sync::RwLock,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader, ReadBuf},
+    process::{Child, ChildStdout, Command},
+};
+
+lazy_static! {
+    /// The ffmpeg executable from the settings, if any.
+    static ref FFMPEG_PATH: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Uses the ffmpeg at `path` and the ffprobe next to it instead of the
+/// ones on the PATH.
+pub(crate) fn set_path(path: Option<PathBuf>) {
+    info!("ffmpeg path: {:?}", path);
+    *FFMPEG_PATH.write().expect("ffmpeg path") = path;
+}
+
+fn ffmpeg() -> PathBuf {
+    match FFMPEG_PATH.read().expect("ffmpeg path").as_ref() {
+        Some(path) => path.clone(),
+        None => PathBuf::from(format!("ffmpeg{}", EXE_SUFFIX)),
+    }
+}
+
+fn ffprobe() -> PathBuf {
+    let name = format!("ffprobe{}", EXE_SUFFIX);
+
+    match FFMPEG_PATH.read().expect("ffmpeg path").as_ref() {
+        Some(path) => path.with_file_name(name),
+        None => PathBuf::from(name),
+    }
+}
+
+/// The stdout of a running ffmpeg process. The process is killed
+/// when this is dropped, e.g. when the client disconnects.
+pub(crate) struct FfmpegStream {
+    // kept alive so the process is not killed before we are done reading
+    _child: Child,
+    stdout: ChildStdout,
+}
+
+impl AsyncRead for FfmpegStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_read(cx, buf)
+    }
+}
+
+/// Spawns ffmpeg with `args` and returns its stdout as a stream.
+/// Anything ffmpeg writes to stderr is logged.
+pub(crate) fn spawn(args: &[OsString]) -> Result<FfmpegStream, Error> {
+    debug!("ffmpeg args: {:#?}", args);
+
+    let mut child = create_command()
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("failed to start ffmpeg")?;
+
+    let stdout = child.stdout.take().context("ffmpeg has no stdout")?;
+
+    if let Some(stderr) = child.stderr.take() {
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                warn!("ffmpeg: {}", line);
+            }
+        });
+    }
+
+    Ok(FfmpegStream {
+        _child: child,
+        stdout,
+    })
+}
+
+/// Runs ffmpeg with `args` to completion, e.g. to write a file.
+pub(crate) async fn run(args: &[OsString]) -> Result<(), Error> {
+    debug!("ffmpeg args: {:#?}", args);
+
+    let output = create_command()
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("failed to start ffmpeg")?;
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(anyhow!(stderr.to_string()))
+    }
+}
+
+/// Progress reported by ffmpeg with `-progress`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Progress {
+    /// Position in the output in seconds.
+    pub(crate) time: f64,
+
+    /// Encoding speed relative to playback, e.g. 2.0 when twice as fast.
+    pub(crate) speed: Option<f64>,
+}
+
+impl Progress {
+    /// Updates the progress from a `key=value` line and returns
+    /// whether it was the last line of a report.
+    fn update(&mut self, line: &str) -> bool {
+        match line.split_once('=') {
+            Some(("out_time_us", value)) => {
+                // the time is N/A until the first frame is written
+                if let Ok(us) = value.trim().parse::<f64>() {
+                    self.time = us / 1_000_000.0;
+                }
+
+                false
+            }
+            Some(("speed", value)) => {
+                self.speed = value.trim().trim_end_matches('x').parse().ok();
+                false
+            }
+            Some(("progress", _)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Runs ffmpeg with `args` to completion like `run`, calling `on_progress`
+/// with each progress report. The process is killed if the future is dropped.
+pub(crate) async fn run_with_progress<F>(args: &[OsString], mut on_progress: F) -> Result<(), Error>
+where
+    F: FnMut(Progress),
+{
+    debug!("ffmpeg args: {:#?}", args);
+
+    let mut child = create_command()
+        .args(["-progress", "pipe:1"])
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("failed to start ffmpeg")?;
+
+    let stdout = child.stdout.take().context("ffmpeg has no stdout")?;
+    let mut stderr = child.stderr.take().context("ffmpeg has no stderr")?;
+
+    // read stderr while ffmpeg runs so it doesn't block on a full pipe
+    let errors = tokio::spawn(async move {
+        let mut errors = String::new();
+        let _ = stderr.read_to_string(&mut errors).await;
+        errors
+    });
+
+    let mut lines = BufReader::new(stdout).lines();
+    let mut progress = Progress::default();
+
+    while let Some(line) = lines.next_line().await? {
+        if progress.update(&line) {
+            on_progress(progress);
+        }
+    }
+
+    let status = child.wait().await.context("failed to wait for ffmpeg")?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(errors.await.unwrap_or_default()))
+    }
+}
+
+/// Runs ffprobe on `path` and returns the format and streams as JSON.
+pub(crate) async fn probe(path: &Path) -> Result<String, Error> {
+    let output = create_probe_command()
+        .args(["-v", "error"])
+        .args(["-show_format", "-show_streams"])
+        .args(["-of", "json"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("failed to start ffprobe")?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(anyhow!(stderr.to_string()))
+    }
+}
+
+/// Returns the timestamp in seconds of the first video packet when
+/// seeking to `time` in `path`, i.e. of the keyframe at or before `time`.
+pub(crate) async fn keyframe_before(path: &Path, time: f64) -> Result<Option<f64>, Error> {
+    let output = create_probe_command()
+        .args(["-v", "error"])
+        .args(["-select_streams", "v:0"])
+        .args(["-read_intervals", &format!("{:.3}%+#1", time)])
+        .args(["-show_entries", "packet=pts_time"])
+        .args(["-of", "csv=p=0"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("failed to start ffprobe")?;
+
+    if output.status.success() {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout
+            .lines()
+            .next()
+            .and_then(|line| line.trim().parse().ok()))
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(anyhow!(stderr.to_string()))
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub(crate) fn create_command() -> Command {
+    const CREATE_NO_WINDOW: u32 = 0x08000000;
+    let mut command = Command::new(ffmpeg());
+    command.creation_flags(CREATE_NO_WINDOW);
+    command
+}
+
+#[cfg(not(target_os = "windows"))]
+pub(crate) fn create_command() -> Command {
+    Command::new(ffmpeg())
+}
+
+#[cfg(target_os = "windows")]
+fn create_probe_command() -> Command {
+    const CREATE_NO_WINDOW: u32 = 0x08000000;
+    let mut command = Command::new(ffprobe());
+    command.creation_flags(CREATE_NO_WINDOW);
+    command
+}
+
+#[cfg(not(target_os = "windows"))]
+fn create_probe_command() -> Command {
+    Command::new(ffprobe())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_progress() {
+        let report = "frame=240\n\
+            out_time_us=N/A\n\
+            out_time_us=10010000\n\
+            speed=2.5x\n\
+            progress=continue";
+
+        let mut progress = Progress::default();
+        let ends: Vec<bool> = report.lines().map(|line| progress.update(line)).collect();
+
+        assert_eq!(ends, [false, false, false, false, true]);
+        assert_eq!(progress.time, 10.01);
+        assert_eq!(progress.speed, Some(2.5));
+
+        progress.update("speed=N/A");
+        assert_eq!(progress.speed, None);
+    }
+}
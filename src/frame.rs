@@ -1,26 +1,40 @@
+use crate::{
+    app_result::AppResult, auth::Paired, ffmpeg::create_command, library::Library,
+    media::MediaRegistry,
+};
 use anyhow::{anyhow, Error};
 use log::debug;
-use rocket::{
-    get,
-    http::ContentType,
-    response::{content::Custom, Debug},
-};
-use tokio::process::Command;
+use rocket::{get, http::ContentType, response::content::Custom, State};
+use std::path::Path;
+
+#[get("/frame?<id>")]
+pub(crate) async fn handler(
+    _paired: Paired,
+    id: &str,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+) -> Result<Option<Custom<Vec<u8>>>, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(None),
+    };
 
-#[get("/frame?<path>")]
-pub(crate) async fn handler(path: String) -> Result<Custom<Vec<u8>>, Debug<Error>> {
     let image = extract_jpeg(&path).await?;
     let content_type = ContentType::JPEG;
     let content = Custom(content_type, image);
-    Ok(content)
+    Ok(Some(content))
 }
 
-async fn extract_jpeg(path: &str) -> Result<Vec<u8>, Error> {
+/// Returns the frame 30 seconds into the video at `path` as a JPEG, which
+/// is empty if the video is shorter.
+pub(crate) async fn extract_jpeg(path: &Path) -> Result<Vec<u8>, Error> {
+    let input = [
+        "-ss",      // seek to
+        "00:00:30", // 30 seconds
+        "-i",       // set input to
+    ];
+
     let args = [
-        "-ss",          // seek to
-        "00:00:30",     // 30 seconds
-        "-i",           // set input to
-        path,           // the path of the video
         "-vframes",     // take n video frame
         "1",            // n = 1
         "-q:v",         // set output quality to
@@ -32,9 +46,14 @@ async fn extract_jpeg(path: &str) -> Result<Vec<u8>, Error> {
         "-",            // pipe to stdout
     ];
 
-    debug!("ffmpeg args: {:#?}", args);
+    debug!("ffmpeg args: {:#?} {:#?} {:#?}", input, path, args);
 
-    let output = create_command().args(&args).output().await?;
+    let output = create_command()
+        .args(input)
+        .arg(path)
+        .args(args)
+        .output()
+        .await?;
 
     if output.status.success() {
         if let Err(e) = String::from_utf8(output.stdout) {
@@ -57,16 +76,3 @@ async fn extract_jpeg(path: &str) -> Result<Vec<u8>, Error> {
         Err(anyhow!(e.to_string()))
     }
 }
-
-#[cfg(target_os = "windows")]
-fn create_command() -> Command {
-    const CREATE_NO_WINDOW: u32 = 0x08000000;
-    let mut command = Command::new("ffmpeg.exe");
-    command.creation_flags(CREATE_NO_WINDOW);
-    command
-}
-
-#[cfg(not(target_os = "windows"))]
-fn create_command() -> Command {
-    Command::new("ffmpeg")
-}
@@ -1,8 +1,7 @@
-use crate::app_result::AppResult;
+use crate::{app_result::AppResult, auth::Paired, library::Library, media::MediaRegistry, sniff};
 use anyhow::Error;
-use directories_next::UserDirs;
 use log::{debug, error, info, trace, warn};
-use rocket::{get, response::Redirect, uri};
+use rocket::{get, http::ContentType, response::Redirect, uri, State};
 use serde::Serialize;
 use std::path::{Path, PathBuf};
 use tokio::fs::{self, DirEntry};
@@ -10,12 +9,20 @@ use tokio::fs::{self, DirEntry};
 const VALID_EXTENSIONS: [&str; 4] = [".avi", ".mkv", ".mp4", ".webm"];
 const PARENT: &str = "..";
 
+/// Extensions of files kept next to videos, which are never sniffed.
+const SIDECAR_EXTENSIONS: [&str; 10] = [
+    "ass", "idx", "log", "nfo", "sfv", "srt", "ssa", "sub", "url", "vtt",
+];
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all(serialize = "camelCase"))]
 struct Item {
     is_dir: bool,
     name: String,
     path: PathBuf,
+
+    /// The media ID of files, used instead of the path in media URLs.
+    id: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -27,35 +34,43 @@ pub(crate) struct Directory {
 }
 
 #[get("/fs")]
-pub(crate) async fn fallback() -> Redirect {
-    let path = if let Some(dirs) = UserDirs::new() {
-        let path = dirs.home_dir().display().to_string();
+pub(crate) async fn fallback(_paired: Paired, library: &State<Library>) -> Redirect {
+    let path = if let Some(home) = library.home() {
+        let path = home.display().to_string();
         info!("fallback dir: {}", path);
         path
     } else {
-        warn!("no user dirs found, default path will be /");
+        warn!("no library roots found, default path will be /");
         "/".to_owned()
     };
 
-    Redirect::permanent(uri!(handler(path)))
+    Redirect::to(uri!(handler(path)))
 }
 
 #[get("/fs?<path>")]
-pub(crate) async fn handler(path: String) -> AppResult<Directory> {
-    dir(&path).await.into()
+pub(crate) async fn handler(
+    _paired: Paired,
+    path: String,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+) -> AppResult<Directory> {
+    info!("reading dir: {}", path);
+
+    match library.check(Path::new(&path)) {
+        Ok(path) => dir(path, library, media).await.into(),
+        Err(err) => err.into(),
+    }
 }
 
-async fn dir(path: &str) -> Result<Directory, Error> {
-    info!("reading dir: {}", path);
-    let path = dunce::canonicalize(path)?;
+async fn dir(path: PathBuf, library: &Library, media: &MediaRegistry) -> Result<Directory, Error> {
     debug!("canonical path: {}", path.display());
-    let parent = get_parent(&path);
+    let parent = get_parent(&path).filter(|parent| library.contains(&parent.path));
     debug!("parent: {:#?}", parent);
     let mut entries = fs::read_dir(&path).await?;
     let mut items = Vec::new();
 
     while let Some(entry) = entries.next_entry().await? {
-        match entry_to_item(&entry).await {
+        match entry_to_item(&entry, media).await {
             Ok(Some(item)) => items.push(item),
             Ok(None) => trace!("ignored file {}", entry.path().display()),
             Err(err) => error!("failed to convert entry to item: {}", err),
@@ -64,6 +79,10 @@ async fn dir(path: &str) -> Result<Directory, Error> {
 
     info!("found {} files in {}", items.len(), path.display());
 
+    if let Err(err) = media.save().await {
+        error!("failed to save media ids: {:#}", err);
+    }
+
     Ok(Directory {
         items,
         parent,
@@ -71,7 +90,7 @@ async fn dir(path: &str) -> Result<Directory, Error> {
     })
 }
 
-async fn entry_to_item(entry: &DirEntry) -> Result<Option<Item>, Error> {
+async fn entry_to_item(entry: &DirEntry, media: &MediaRegistry) -> Result<Option<Item>, Error> {
     trace!("entry to item for {:#?}", entry);
 
     let file_type = entry.file_type().await?;
@@ -81,13 +100,24 @@ async fn entry_to_item(entry: &DirEntry) -> Result<Option<Item>, Error> {
         return Ok(None);
     }
 
-    let item = if ignore(&name, file_type.is_file()) {
+    let ignored = ignore(&name, file_type.is_file()) && !is_media(entry, &name).await;
+
+    let item = if ignored {
         None
     } else {
+        let is_dir = file_type.is_dir();
+        let path = entry.path();
+        let id = if is_dir {
+            None
+        } else {
+            Some(media.register(&path))
+        };
+
         Some(Item {
-            is_dir: file_type.is_dir(),
+            is_dir,
             name,
-            path: entry.path(),
+            path,
+            id,
         })
     };
 
@@ -109,6 +139,48 @@ async fn is_hidden(_entry: &DirEntry) -> Result<bool, Error> {
     Ok(false)
 }
 
+/// Whether the file is a video with an odd or missing extension.
+async fn is_media(entry: &DirEntry, name: &str) -> bool {
+    if !may_be_media(name) {
+        return false;
+    }
+
+    match sniff::sniff(&entry.path()).await {
+        Ok(Some(container)) => {
+            debug!("detected {:?} in {}", container, name);
+            true
+        }
+        Ok(None) => false,
+        Err(err) => {
+            trace!("failed to sniff {}: {}", name, err);
+            false
+        }
+    }
+}
+
+/// Whether the file could be a video judging by its name, i.e. it has no
+/// extension or one that isn't known to be something else. Only those are
+/// sniffed, since reading every file makes listing large folders slow.
+fn may_be_media(name: &str) -> bool {
+    if name.starts_with('.') {
+        return false;
+    }
+
+    let ext = match Path::new(name).extension() {
+        Some(ext) => ext.to_string_lossy().to_lowercase(),
+        None => return true,
+    };
+
+    if SIDECAR_EXTENSIONS.contains(&ext.as_str()) {
+        return false;
+    }
+
+    match ContentType::from_extension(&ext) {
+        Some(content_type) => content_type.top() == "video",
+        None => true,
+    }
+}
+
 fn ignore(name: &str, is_file: bool) -> bool {
     fn is_hidden(s: &str) -> bool {
         s.starts_with('.')
@@ -131,6 +203,7 @@ fn get_parent(path: &Path) -> Option<Item> {
             .unwrap_or(PARENT)
             .to_owned(),
         path: path.to_path_buf(),
+        id: None,
     })
 }
 
@@ -154,4 +227,22 @@ mod tests {
             ignore(name, is_file)
         }
     }
+
+    mod may_be_media {
+        use super::may_be_media;
+        use test_case::test_case;
+
+        #[test_case("video" => true; "without extension")]
+        #[test_case("video.2020" => true; "with unknown extension")]
+        #[test_case("video.m2ts" => true; "with unlisted video extension")]
+        #[test_case("video.MOV" => true; "with upper case video extension")]
+        #[test_case("video.txt" => false; "with text extension")]
+        #[test_case("poster.jpg" => false; "with image extension")]
+        #[test_case("video.nfo" => false; "with nfo extension")]
+        #[test_case("video.en.srt" => false; "with subtitle extension")]
+        #[test_case(".video" => false; "when hidden")]
+        fn works(name: &str) -> bool {
+            may_be_media(name)
+        }
+    }
 }
@@ -1,16 +1,17 @@
+use crate::auth::Paired;
 use anyhow::Error;
 use log::info;
 use rocket::{get, response::Debug, serde::json::Json};
 use std::net::{IpAddr, UdpSocket};
 
 #[get("/ip")]
-pub(crate) fn handler() -> Result<Json<IpAddr>, Debug<Error>> {
+pub(crate) fn handler(_paired: Paired) -> Result<Json<IpAddr>, Debug<Error>> {
     let ip = get_local_ip()?;
     info!("local ip: {}", ip);
     Ok(Json(ip))
 }
 
-fn get_local_ip() -> Result<IpAddr, Error> {
+pub(crate) fn get_local_ip() -> Result<IpAddr, Error> {
     let ip = UdpSocket::bind("0.0.0.0:0")
         .and_then(|socket| {
             let _ = socket.connect("1.1.1.1:80");
@@ -0,0 +1,167 @@
+//! Library roots are the directories the server may read from, since it
+//! listens on the LAN and would otherwise hand out any file on the machine.
+//! Paths are canonicalized before they are checked, so symlinks pointing
+//! out of a root are rejected too.
+use crate::app_result::AppResult;
+use directories_next::UserDirs;
+use log::{info, warn};
+use rocket::http::Status;
+use serde::Serialize;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub(crate) enum AccessError {
+    #[error("{} is outside the library", .0.display())]
+    Forbidden(PathBuf),
+
+    #[error("{} was not found: {}", .0.display(), .1)]
+    NotFound(PathBuf, io::Error),
+}
+
+impl<T: Serialize> From<AccessError> for AppResult<T> {
+    fn from(err: AccessError) -> Self {
+        let status = match err {
+            AccessError::Forbidden(_) => Status::Forbidden,
+            AccessError::NotFound(..) => Status::NotFound,
+        };
+
+        AppResult::error(err).with_status(status)
+    }
+}
+
+/// The canonical library roots, which can be changed while the server runs.
+#[derive(Debug, Clone)]
+pub(crate) struct Library {
+    roots: Arc<RwLock<Vec<PathBuf>>>,
+}
+
+impl Library {
+    /// Uses the home directory if no `roots` are given.
+    pub(crate) fn new(roots: &[PathBuf]) -> Self {
+        Self {
+            roots: Arc::new(RwLock::new(canonical_roots(roots))),
+        }
+    }
+
+    pub(crate) fn set_roots(&self, roots: &[PathBuf]) {
+        *self.roots.write().expect("library roots") = canonical_roots(roots);
+    }
+
+    /// The directory shown first, i.e. the first root.
+    pub(crate) fn home(&self) -> Option<PathBuf> {
+        self.roots.read().expect("library roots").first().cloned()
+    }
+
+    /// Returns the canonical `path` if it is inside a library root.
+    pub(crate) fn check(&self, path: &Path) -> Result<PathBuf, AccessError> {
+        let canonical = dunce::canonicalize(path)
+            .map_err(|err| AccessError::NotFound(path.to_path_buf(), err))?;
+
+        if self.contains(&canonical) {
+            Ok(canonical)
+        } else {
+            warn!("denied access to {}", canonical.display());
+            Err(AccessError::Forbidden(path.to_path_buf()))
+        }
+    }
+
+    /// Whether the canonical `path` is inside a library root.
+    pub(crate) fn contains(&self, path: &Path) -> bool {
+        let roots = self.roots.read().expect("library roots");
+        roots.iter().any(|root| path.starts_with(root))
+    }
+}
+
+fn canonical_roots(roots: &[PathBuf]) -> Vec<PathBuf> {
+    let configured = if roots.is_empty() {
+        UserDirs::new()
+            .map(|dirs| vec![dirs.home_dir().to_path_buf()])
+            .unwrap_or_default()
+    } else {
+        roots.to_vec()
+    };
+
+    let roots = configured
+        .into_iter()
+        .filter_map(|root| match dunce::canonicalize(&root) {
+            Ok(root) => Some(root),
+            Err(err) => {
+                warn!("ignoring library root {}: {}", root.display(), err);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    info!("library roots: {:?}", roots);
+    roots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    fn library(name: &str) -> (PathBuf, Library) {
+        let dir = env::temp_dir().join(format!("videocaster-library-{}", name));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("movies")).unwrap();
+        fs::write(root.join("movies").join("a.mkv"), b"").unwrap();
+        fs::write(dir.join("secret.txt"), b"").unwrap();
+
+        (dir, Library::new(&[root]))
+    }
+
+    #[test]
+    fn allows_paths_inside_roots() {
+        let (dir, library) = library("inside");
+        let path = dir
+            .join("root")
+            .join("movies")
+            .join("..")
+            .join("movies")
+            .join("a.mkv");
+
+        let checked = library.check(&path).unwrap();
+        assert!(checked.ends_with("root/movies/a.mkv"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_paths_outside_roots() {
+        let (dir, library) = library("outside");
+        let path = dir.join("root").join("..").join("secret.txt");
+
+        assert!(matches!(
+            library.check(&path),
+            Err(AccessError::Forbidden(_))
+        ));
+        assert!(matches!(
+            library.check(&dir.join("missing.mkv")),
+            Err(AccessError::NotFound(..))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_out_of_roots() {
+        let (dir, library) = library("symlink");
+        let link = dir.join("root").join("secret.txt");
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(dir.join("secret.txt"), &link).unwrap();
+
+        assert!(matches!(
+            library.check(&link),
+            Err(AccessError::Forbidden(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,62 +1,121 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod app_result;
+mod auth;
+mod browser;
 mod chromecast;
+mod cli;
+mod ffmpeg;
 mod frame;
 mod fs;
 mod ip;
+mod library;
+mod media;
 mod opensubs;
+mod probe;
+mod profile;
+mod settings;
+mod sniff;
 mod static_files;
 mod subtitles;
+mod tls;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use auth::{Auth, Paired};
+use chromecast::{
+    audio::AudioConfig,
+    hls::HlsCache,
+    jobs::{JobConfig, JobQueue},
+    traffic::{Traffic, TrafficConfig},
+    transcode::START_TIME_HEADER,
+    video::VideoConfig,
+};
+use cli::Command;
 use directories_next::ProjectDirs;
 use futures::{future, pin_mut};
+use library::Library;
 use log::{debug, error, info, warn, LevelFilter};
+use media::MediaRegistry;
+use profile::ProfileConfig;
 use rocket::{
-    catchers,
-    figment::{
-        providers::{Env, Format, Toml},
-        Figment,
-    },
-    http::Method,
-    post, routes, Build, Config, Ignite, Rocket, Shutdown,
+    catchers, fairing::AdHoc, figment::Figment, http::Method, post, routes, Build, Config, Ignite,
+    Rocket, Shutdown,
 };
 use rocket_cors::{AllowedHeaders, AllowedOrigins, CorsOptions};
-use std::path::{Path, PathBuf};
-use tokio::{io::AsyncWriteExt, process::Command};
+use settings::{Settings, SettingsStore};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tls::{HttpsConfig, TlsInfo};
+use tokio::io::AsyncWriteExt;
 
 const CONFIG_PATH: &str = "Videocaster.toml";
-const ENV_PREFIX: &str = "VIDEOCASTER_";
 
 #[rocket::main]
 async fn main() -> Result<()> {
     color_backtrace::install();
+    cli::attach_console();
+
+    let command = cli::parse(std::env::args_os().skip(1))
+        .map_err(|err| anyhow!("{}\n\n{}", err, cli::USAGE))?;
+
     let config_path = create_config_file().await?;
     let _ = configure_logging();
-    let rocket = create_rocket(&config_path).ignite().await?;
+    let settings = load_settings(&config_path).await?;
+    let figment = settings::figment(&config_path);
+
+    match command {
+        Command::Serve { no_browser } => serve(&config_path, settings, figment, no_browser).await,
+        command => {
+            settings::apply_globals(&settings);
+            cli::run(command, &settings, &figment).await
+        }
+    }
+}
+
+async fn serve(
+    config_path: &Path,
+    settings: Settings,
+    figment: Figment,
+    no_browser: bool,
+) -> Result<()> {
+    let (https, tls_info) = configure_https(&figment).await;
+    let state = AppState::new(&figment, config_path, &settings, tls_info);
+    let hls_cleanup = state.hls_cache.clone();
+    let settings_watcher = state.settings.clone();
+    let rocket = create_rocket(figment, state.clone())
+        .attach(AdHoc::on_liftoff("HLS cleanup", |_| {
+            Box::pin(async move {
+                tokio::spawn(hls_cleanup.clean_up());
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Settings watcher", |_| {
+            Box::pin(async move {
+                tokio::spawn(settings_watcher.watch());
+            })
+        }))
+        .ignite()
+        .await?;
     let config = rocket.config().to_owned();
-    let chrome = start_google_chrome(&config);
-    let server = start_rocket(rocket);
-
-    if cfg!(target_os = "windows") {
-        // Chrome on Windows returns immediately after launch. This means we can't rely
-        // on closing Chrome to notify us to stop via futures. To fix this, we register
-        // a handler to signal for shutdown. (If the server is stopped via other means,
-        // Chrome won't be closed automatically.)
-        future::join(server, chrome).await;
+    let server = start_servers(rocket, https, state);
+
+    if no_browser {
+        // e.g. as a background service, which runs until it is stopped
+        info!("not opening a browser");
+        server.await;
     } else {
-        // Chrome on not-Windows does not return until the last window is closed. If the
-        // server is closed via ctrl+c, we also close Chrome automatically.
-        pin_mut!(server, chrome);
-        future::select(server, chrome).await;
-    };
+        // the server stops when the browser is closed
+        let browser = start_browser(&config, &settings);
+        pin_mut!(server, browser);
+        future::select(server, browser).await;
+    }
 
     Ok(())
 }
 
 #[post("/shutdown")]
-pub(crate) async fn shutdown(shutdown: Shutdown) {
+pub(crate) async fn shutdown(_paired: Paired, shutdown: Shutdown) {
     shutdown.notify()
 }
 
@@ -80,107 +139,269 @@ fn configure_logging() -> Result<()> {
 async fn create_config_file() -> Result<PathBuf> {
     let dirs = open_project_dirs().ok_or_else(|| anyhow!("failed to open project dirs"))?;
     let mut path = dirs.config_dir().to_path_buf();
+    tokio::fs::create_dir_all(&path).await?;
     path.push(CONFIG_PATH);
 
     debug!("config file path: {}", path.display());
 
     let file = tokio::fs::OpenOptions::new()
-        .create(true)
+        .create_new(true)
         .write(true)
         .open(&path)
         .await;
 
-    if let Ok(mut file) = file {
-        let default_config = include_bytes!("../Release.toml");
-        file.write_all(default_config).await?;
-        info!("re-created default config file");
-    } else {
-        debug!("config file exists, won't overwrite");
+    match file {
+        Ok(mut file) => {
+            let default_config = include_bytes!("../Release.toml");
+            file.write_all(default_config).await?;
+            info!("created default config file");
+        }
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            debug!("config file exists, won't overwrite");
+        }
+        Err(err) => return Err(err).context("failed to create config file"),
     }
 
     Ok(path)
 }
 
-fn create_rocket<P: AsRef<Path>>(config_path: P) -> Rocket<Build> {
-    let figment = Figment::from(Config::default())
-        .merge(Toml::file(config_path).nested())
-        .merge(Env::prefixed(ENV_PREFIX).global());
+/// Loads the settings and logs why they are invalid, since the
+/// console is hidden in release builds on Windows.
+async fn load_settings(config_path: &Path) -> Result<Settings> {
+    let settings = settings::load(config_path)
+        .await
+        .with_context(|| format!("failed to load {}", config_path.display()));
+
+    if let Err(err) = &settings {
+        error!("{:#}", err);
+    }
+
+    settings
+}
+
+/// Returns the figment of the HTTPS listener if it is enabled and its
+/// certificate could be loaded.
+async fn configure_https(figment: &Figment) -> (Option<Figment>, TlsInfo) {
+    let https_config = figment.extract::<HttpsConfig>().expect("https config");
+    let http_port = figment.extract_inner::<u16>("port").expect("port");
+
+    let http_only = TlsInfo {
+        http_port,
+        ..TlsInfo::default()
+    };
+
+    let port = match https_config.https_port {
+        Some(port) => port,
+        None => return (None, http_only),
+    };
+
+    #[cfg(feature = "tls")]
+    {
+        let dir = match open_project_dirs() {
+            Some(dirs) => dirs.config_dir().join("tls"),
+            None => std::env::temp_dir().join("videocaster-tls"),
+        };
+
+        match tls::load_or_create(&dir).await {
+            Ok(certificate) => {
+                use rocket::figment::providers::Serialized;
+
+                // global, so the port of the HTTP listener in the environment isn't used
+                let figment = figment
+                    .clone()
+                    .merge(Serialized::global("port", port))
+                    .merge(Serialized::global("tls.certs", &certificate.cert))
+                    .merge(Serialized::global("tls.key", &certificate.key));
+
+                let info = TlsInfo {
+                    port: Some(port),
+                    http_port,
+                    fingerprint: Some(certificate.fingerprint),
+                };
+
+                return (Some(figment), info);
+            }
+            Err(err) => error!("failed to load tls certificate: {:#}", err),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    warn!(
+        "https_port {} is ignored, built without the tls feature",
+        port
+    );
+
+    (None, http_only)
+}
+
+/// State shared by the HTTP and HTTPS listeners, so sessions, media IDs
+/// and jobs are the same on both.
+#[derive(Clone)]
+struct AppState {
+    auth: Auth,
+    library: Library,
+    media: MediaRegistry,
+    hls_cache: HlsCache,
+    jobs: JobQueue,
+    traffic: Traffic,
+    tls: TlsInfo,
+    settings: SettingsStore,
+}
+
+impl AppState {
+    fn new(figment: &Figment, config_path: &Path, settings: &Settings, tls: TlsInfo) -> Self {
+        let job_config = figment.extract::<JobConfig>().expect("job config");
+        let traffic_config = figment.extract::<TrafficConfig>().expect("traffic config");
+        let library = Library::new(&settings.library_roots);
+
+        let jobs_dir = match open_project_dirs() {
+            Some(dirs) => dirs.cache_dir().join("transcoded"),
+            None => std::env::temp_dir().join("videocaster-transcoded"),
+        };
+
+        let media_file = match open_project_dirs() {
+            Some(dirs) => dirs.data_dir().join("media.json"),
+            None => std::env::temp_dir().join("videocaster-media.json"),
+        };
 
+        Self {
+            auth: Auth::new(),
+            library: library.clone(),
+            media: MediaRegistry::load(&media_file),
+            hls_cache: HlsCache::new(),
+            jobs: JobQueue::new(jobs_dir, job_config.transcode_workers),
+            traffic: Traffic::new(&traffic_config),
+            tls,
+            settings: SettingsStore::new(config_path, settings.clone(), library),
+        }
+    }
+}
+
+fn create_rocket(figment: Figment, state: AppState) -> Rocket<Build> {
     let routes = routes![
+        auth::pair,
+        auth::pin,
+        auth::status,
+        auth::token,
+        chromecast::hls::index,
+        chromecast::hls::master,
+        chromecast::hls::segment,
+        chromecast::jobs::cancel,
+        chromecast::jobs::enqueue,
+        chromecast::jobs::list,
+        chromecast::jobs::retry,
+        chromecast::remux::handler,
+        chromecast::remux::head,
         chromecast::subtitles::handler,
+        chromecast::traffic::handler,
+        chromecast::transcode::audio_handler,
+        chromecast::transcode::audio_head,
+        chromecast::transcode::handler,
+        chromecast::transcode::head,
         chromecast::video::handler,
         frame::handler,
         fs::fallback,
         fs::handler,
         ip::handler,
+        probe::handler,
+        settings::handler,
+        settings::update,
         shutdown,
         static_files::file,
         subtitles::by_metadata::handler,
         subtitles::by_path::handler,
+        tls::handler,
     ];
 
-    let catchers = catchers![static_files::fallback];
+    let catchers = catchers![auth::unauthorized, static_files::fallback];
 
     let config = figment.extract::<Config>().expect("config");
     let rocket = rocket::custom(figment);
-    let port = config.port;
-    let host = format!("http://localhost:{}", port);
+    let scheme = if config.tls_enabled() {
+        "https"
+    } else {
+        "http"
+    };
+    let host = format!("{}://localhost:{}", scheme, config.port);
     let cors = CorsOptions {
         allowed_headers: AllowedHeaders::some(&["Accept-Encoding", "Content-Type", "Range"]),
-        allowed_methods: vec![Method::Get].into_iter().map(From::from).collect(),
+        allowed_methods: vec![Method::Get, Method::Head]
+            .into_iter()
+            .map(From::from)
+            .collect(),
         allowed_origins: AllowedOrigins::some_exact(&["https://www.gstatic.com", &host]),
+        expose_headers: [START_TIME_HEADER.to_owned()].into_iter().collect(),
         ..Default::default()
     }
     .to_cors()
     .expect("CORS options are invalid");
 
+    let store = state.settings.clone();
+
     rocket
         .mount("/", routes)
         .register("/", catchers)
+        .manage(state.auth)
+        .manage(state.library)
+        .manage(state.media)
+        .manage(state.hls_cache)
+        .manage(state.jobs)
+        .manage(state.traffic)
+        .manage(state.tls)
+        .manage(state.settings)
         .attach(cors)
+        .attach(AdHoc::config::<AudioConfig>())
+        .attach(AdHoc::config::<ProfileConfig>())
+        .attach(AdHoc::config::<VideoConfig>())
+        // igniting sets the log level from Rocket's config, so ours is set again
+        .attach(AdHoc::on_liftoff("Log level", |_| {
+            Box::pin(async move {
+                settings::apply_globals(&store.get());
+            })
+        }))
 }
 
-async fn start_rocket(rocket: Rocket<Ignite>) {
-    if let Err(e) = rocket.launch().await {
-        error!("Rocket failed to launch: {}", e);
-    }
+/// Runs the HTTP listener and the HTTPS listener, if any, until one stops.
+async fn start_servers(rocket: Rocket<Ignite>, https: Option<Figment>, state: AppState) {
+    let http = start_rocket(rocket);
+    let https = start_https(https, state);
+    pin_mut!(http, https);
+    future::select(http, https).await;
 }
 
-async fn start_google_chrome(config: &Config) {
-    #[cfg(target_os = "windows")]
-    fn create_command() -> Command {
-        const DETACHED_PROCESS: u32 = 0x00000008;
-        let mut command = Command::new("cmd");
-        command.args(&["/C", "start", "chrome"]);
-        command.creation_flags(DETACHED_PROCESS);
-        command
+async fn start_https(figment: Option<Figment>, state: AppState) {
+    if let Some(figment) = figment {
+        match create_rocket(figment, state).ignite().await {
+            Ok(rocket) => return start_rocket(rocket).await,
+            Err(e) => error!("HTTPS listener failed to start: {}", e),
+        }
     }
 
-    #[cfg(not(target_os = "windows"))]
-    fn create_command() -> Command {
-        Command::new("google-chrome")
+    // the HTTP listener keeps serving receivers either way
+    future::pending().await
+}
+
+async fn start_rocket(rocket: Rocket<Ignite>) {
+    if let Err(e) = rocket.launch().await {
+        error!("Rocket failed to launch: {}", e);
     }
+}
 
+/// Opens the UI and returns when the browser is closed. If no browser
+/// could be opened, the server keeps running for other devices.
+async fn start_browser(config: &Config, settings: &Settings) {
     let url = format!("http://localhost:{}", config.port);
+    let dirs = open_project_dirs();
+    let user_data_dir = dirs.as_ref().map(ProjectDirs::config_dir);
 
-    let user_data_dir = {
-        if let Some(dirs) = open_project_dirs() {
-            let path = dirs.config_dir().display();
-            info!("project config dir: {}", path);
-            format!("--user-data-dir={}", path)
-        } else {
-            warn!("no project dirs found, using chrome's default data dir");
-            "".to_owned()
-        }
-    };
-
-    let args = [&url, &user_data_dir, "--no-default-browser-check"];
-
-    debug!("chrome args: {:#?}", args);
+    match user_data_dir {
+        Some(dir) => info!("project config dir: {}", dir.display()),
+        None => warn!("no project dirs found, using the browser's default data dir"),
+    }
 
-    match create_command().args(&args).status().await {
-        Ok(exit) => info!("google chrome stopped with code {}", exit),
-        Err(err) => error!("failed to open google chrome: {}", err),
+    if let Err(err) = browser::run(&url, user_data_dir, settings).await {
+        error!("{:#}, open {} yourself", err, url);
+        future::pending::<()>().await;
     }
 }
 
@@ -0,0 +1,196 @@
+//! Media IDs stand in for file paths in URLs, so receivers never see the
+//! directory layout and clients can only read files that have been listed.
+//! IDs are random, so they can't be guessed, and are saved, so a file keeps
+//! its ID across restarts and URLs receivers already have keep working.
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Maps media IDs to the paths of the files they were handed out for.
+#[derive(Clone, Default)]
+pub(crate) struct MediaRegistry {
+    ids: Arc<Mutex<Ids>>,
+
+    /// Where the IDs are saved, if anywhere.
+    file: Option<Arc<PathBuf>>,
+
+    // one save at a time, so an older one can't overwrite a newer one
+    saving: Arc<AsyncMutex<()>>,
+}
+
+#[derive(Default)]
+struct Ids {
+    paths: HashMap<String, PathBuf>,
+    by_path: HashMap<PathBuf, String>,
+
+    /// Whether IDs were handed out since they were last saved.
+    changed: bool,
+}
+
+impl MediaRegistry {
+    /// Loads the IDs saved in `file`, which new IDs are saved to as well.
+    pub(crate) fn load(file: &Path) -> Self {
+        let paths = match std::fs::read(file) {
+            Ok(json) => {
+                serde_json::from_slice::<HashMap<String, PathBuf>>(&json).unwrap_or_else(|err| {
+                    warn!("ignoring invalid media ids in {}: {}", file.display(), err);
+                    HashMap::new()
+                })
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                warn!("failed to read media ids from {}: {}", file.display(), err);
+                HashMap::new()
+            }
+        };
+
+        info!("loaded {} media ids from {}", paths.len(), file.display());
+
+        let by_path = paths
+            .iter()
+            .map(|(id, path)| (path.clone(), id.clone()))
+            .collect();
+
+        Self {
+            ids: Arc::new(Mutex::new(Ids {
+                paths,
+                by_path,
+                changed: false,
+            })),
+            file: Some(Arc::new(file.to_path_buf())),
+            saving: Arc::default(),
+        }
+    }
+
+    /// Saves the IDs if new ones were handed out since they were last saved.
+    pub(crate) async fn save(&self) -> Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let _saving = self.saving.lock().await;
+
+        let json = {
+            let mut ids = self.ids.lock().expect("media ids");
+
+            if !ids.changed {
+                return Ok(());
+            }
+
+            ids.changed = false;
+
+            // JSON can't hold paths that aren't UTF-8, those get new IDs after a restart
+            let paths = ids
+                .paths
+                .iter()
+                .filter(|(_, path)| path.to_str().is_some())
+                .collect::<HashMap<_, _>>();
+            serde_json::to_vec(&paths)?
+        };
+
+        let result = write(file, &json).await;
+
+        if result.is_err() {
+            self.ids.lock().expect("media ids").changed = true;
+        }
+
+        result
+    }
+
+    /// Returns the ID of the file at `path`, creating one if the file has none.
+    pub(crate) fn register(&self, path: &Path) -> String {
+        let mut ids = self.ids.lock().expect("media ids");
+
+        if let Some(id) = ids.by_path.get(path) {
+            return id.clone();
+        }
+
+        let id = loop {
+            let id = format!("{:032x}", rand::random::<u128>());
+
+            if !ids.paths.contains_key(&id) {
+                break id;
+            }
+        };
+
+        debug!("media id {} for {}", id, path.display());
+        ids.paths.insert(id.clone(), path.to_path_buf());
+        ids.by_path.insert(path.to_path_buf(), id.clone());
+        ids.changed = true;
+        id
+    }
+
+    /// Returns the path of the file with the media `id`.
+    pub(crate) fn path(&self, id: &str) -> Option<PathBuf> {
+        let ids = self.ids.lock().expect("media ids");
+        let path = ids.paths.get(id).cloned();
+
+        if path.is_none() {
+            debug!("unknown media id {}", id);
+        }
+
+        path
+    }
+}
+
+/// Writes to a temporary file first, so the IDs aren't lost if writing fails.
+async fn write(file: &Path, json: &[u8]) -> Result<()> {
+    if let Some(dir) = file.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+
+    let part = file.with_extension("part");
+    tokio::fs::write(&part, json)
+        .await
+        .with_context(|| format!("failed to write {}", part.display()))?;
+    tokio::fs::rename(&part, file).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_ids_stable() {
+        let media = MediaRegistry::default();
+        let id = media.register(Path::new("/videos/a.mkv"));
+
+        assert_eq!(id.len(), 32);
+        assert_eq!(media.register(Path::new("/videos/a.mkv")), id);
+        assert_ne!(media.register(Path::new("/videos/b.mkv")), id);
+        assert_eq!(media.path(&id), Some(PathBuf::from("/videos/a.mkv")));
+    }
+
+    #[tokio::test]
+    async fn keeps_ids_across_restarts() {
+        let file = std::env::temp_dir().join("videocaster-media-test.json");
+        let _ = std::fs::remove_file(&file);
+
+        let media = MediaRegistry::load(&file);
+        let id = media.register(Path::new("/videos/a.mkv"));
+        media.save().await.unwrap();
+
+        let restarted = MediaRegistry::load(&file);
+        assert_eq!(restarted.path(&id), Some(PathBuf::from("/videos/a.mkv")));
+        assert_eq!(restarted.register(Path::new("/videos/a.mkv")), id);
+
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_ids() {
+        let media = MediaRegistry::default();
+        media.register(Path::new("/videos/a.mkv"));
+
+        assert_eq!(media.path("/videos/a.mkv"), None);
+        assert_eq!(media.path("00000000000000000000000000000000"), None);
+    }
+}
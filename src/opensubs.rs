@@ -58,7 +58,7 @@ fn build_client() -> Result<Client> {
 }
 
 // https://raw.githubusercontent.com/nwoltman/srt-to-vtt-converter/master/SRT%20to%20VTT%20Converter/SubtitleConverter.cs
-fn srt_to_vtt(srt: &str) -> String {
+pub(crate) fn srt_to_vtt(srt: &str) -> String {
     lazy_static! {
         static ref CUE_ID_REGEX: Regex =
             Regex::new(r"^\d+$").expect("cue id regex compilation failed");
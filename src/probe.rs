@@ -0,0 +1,441 @@
+//! This module describes media files using ffprobe and decides
+//! whether the Chromecast can play them as is.
+use crate::{
+    app_result::AppResult,
+    auth::Paired,
+    ffmpeg,
+    library::Library,
+    media::MediaRegistry,
+    profile::{Profile, ProfileConfig},
+};
+use anyhow::{Context, Error};
+use log::{debug, info};
+use rocket::{get, State};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::Path};
+
+const IMAGE_SUBTITLE_CODECS: [&str; 4] =
+    ["dvb_subtitle", "dvd_subtitle", "hdmv_pgs_subtitle", "xsub"];
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) struct MediaInfo {
+    pub(crate) container: Option<String>,
+    pub(crate) duration: Option<f64>,
+    pub(crate) bit_rate: Option<u64>,
+    pub(crate) video: Vec<VideoStream>,
+    pub(crate) audio: Vec<AudioStream>,
+    pub(crate) subtitles: Vec<SubtitleStream>,
+    pub(crate) verdict: Verdict,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) struct VideoStream {
+    pub(crate) index: u32,
+    pub(crate) codec: Option<String>,
+    pub(crate) profile: Option<String>,
+    pub(crate) level: Option<i32>,
+    pub(crate) width: Option<u32>,
+    pub(crate) height: Option<u32>,
+    pub(crate) frame_rate: Option<f64>,
+    pub(crate) bit_rate: Option<u64>,
+    pub(crate) pixel_format: Option<String>,
+    pub(crate) color_transfer: Option<String>,
+    pub(crate) color_primaries: Option<String>,
+    pub(crate) hdr: Option<Hdr>,
+    pub(crate) dolby_vision: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) struct AudioStream {
+    pub(crate) index: u32,
+    pub(crate) codec: Option<String>,
+    pub(crate) language: Option<String>,
+    pub(crate) title: Option<String>,
+    pub(crate) channels: Option<u32>,
+    pub(crate) channel_layout: Option<String>,
+    pub(crate) bit_rate: Option<u64>,
+    pub(crate) default: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) struct SubtitleStream {
+    pub(crate) index: u32,
+    pub(crate) codec: Option<String>,
+    pub(crate) language: Option<String>,
+    pub(crate) title: Option<String>,
+    pub(crate) default: bool,
+    pub(crate) forced: bool,
+    pub(crate) image_based: bool,
+}
+
+/// HDR transfer characteristics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Hdr {
+    /// Perceptual quantizer, used by HDR10 and Dolby Vision.
+    Pq,
+
+    /// Hybrid log-gamma.
+    Hlg,
+}
+
+/// How a file can be played on the Chromecast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) enum Verdict {
+    /// The file can be streamed as is.
+    DirectPlay,
+
+    /// The streams can be played, but the container can't.
+    Remux,
+
+    /// The video stream can be played, but the audio stream must be re-encoded.
+    TranscodeAudio,
+
+    /// The video stream can be played, but not in its resolution.
+    Downscale,
+
+    /// The streams must be re-encoded.
+    Transcode,
+}
+
+/// The verdict is for the first audio track and the receiver with the
+/// `profile` name or `device` name, see `ProfileConfig::resolve`.
+#[get("/probe?<id>&<profile>&<device>")]
+pub(crate) async fn handler(
+    _paired: Paired,
+    id: &str,
+    profile: Option<&str>,
+    device: Option<&str>,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    config: &State<ProfileConfig>,
+) -> AppResult<MediaInfo> {
+    let path = match media.path(id).map(|path| library.check(&path)) {
+        Some(Ok(path)) => path,
+        Some(Err(err)) => return err.into(),
+        None => return AppResult::error(format!("unknown media id {}", id)),
+    };
+
+    let result = probe(&path).await.map(|mut info| {
+        let profile = config.resolve(profile, device);
+        info.verdict = profile.verdict(&info, info.audio.first());
+        info
+    });
+
+    result.into()
+}
+
+pub(crate) async fn probe(path: &Path) -> Result<MediaInfo, Error> {
+    info!("probing {}", path.display());
+    let json = ffmpeg::probe(path).await?;
+    let output: FfprobeOutput = serde_json::from_str(&json)
+        .with_context(|| format!("failed to parse ffprobe output for {}", path.display()))?;
+    let info = MediaInfo::from(output);
+    debug!("media info: {:#?}", info);
+    Ok(info)
+}
+
+impl From<FfprobeOutput> for MediaInfo {
+    fn from(output: FfprobeOutput) -> Self {
+        let mut video = Vec::new();
+        let mut audio = Vec::new();
+        let mut subtitles = Vec::new();
+
+        for stream in output.streams {
+            match stream.codec_type.as_deref() {
+                Some("video") if !stream.is_attached_pic() => video.push(VideoStream {
+                    index: stream.index,
+                    hdr: stream.hdr(),
+                    dolby_vision: stream.is_dolby_vision(),
+                    frame_rate: stream.avg_frame_rate.as_deref().and_then(parse_ratio),
+                    bit_rate: parse(&stream.bit_rate),
+                    codec: stream.codec_name,
+                    profile: stream.profile,
+                    level: stream.level,
+                    width: stream.width,
+                    height: stream.height,
+                    pixel_format: stream.pix_fmt,
+                    color_transfer: stream.color_transfer,
+                    color_primaries: stream.color_primaries,
+                }),
+                Some("audio") => audio.push(AudioStream {
+                    index: stream.index,
+                    language: stream.tags.get("language").cloned(),
+                    title: stream.tags.get("title").cloned(),
+                    default: stream.disposition.get("default") == Some(&1),
+                    bit_rate: parse(&stream.bit_rate),
+                    codec: stream.codec_name,
+                    channels: stream.channels,
+                    channel_layout: stream.channel_layout,
+                }),
+                Some("subtitle") => subtitles.push(SubtitleStream {
+                    index: stream.index,
+                    language: stream.tags.get("language").cloned(),
+                    title: stream.tags.get("title").cloned(),
+                    default: stream.disposition.get("default") == Some(&1),
+                    forced: stream.disposition.get("forced") == Some(&1),
+                    image_based: matches!(
+                        stream.codec_name.as_deref(),
+                        Some(codec) if IMAGE_SUBTITLE_CODECS.contains(&codec)
+                    ),
+                    codec: stream.codec_name,
+                }),
+                _ => {}
+            }
+        }
+
+        let mut info = Self {
+            container: output.format.format_name,
+            duration: parse(&output.format.duration),
+            bit_rate: parse(&output.format.bit_rate),
+            video,
+            audio,
+            subtitles,
+            verdict: Verdict::Transcode,
+        };
+
+        info.verdict = Profile::default().verdict(&info, info.audio.first());
+        info
+    }
+}
+
+fn parse<T: std::str::FromStr>(s: &Option<String>) -> Option<T> {
+    s.as_deref().and_then(|s| s.parse().ok())
+}
+
+fn parse_ratio(s: &str) -> Option<f64> {
+    let (numerator, denominator) = s.split_once('/')?;
+    let numerator: f64 = numerator.parse().ok()?;
+    let denominator: f64 = denominator.parse().ok()?;
+
+    if denominator == 0.0 {
+        None
+    } else {
+        Some(numerator / denominator)
+    }
+}
+
+#[derive(Deserialize)]
+struct FfprobeOutput {
+    #[serde(default)]
+    streams: Vec<FfprobeStream>,
+
+    #[serde(default)]
+    format: FfprobeFormat,
+}
+
+#[derive(Default, Deserialize)]
+struct FfprobeFormat {
+    format_name: Option<String>,
+    duration: Option<String>,
+    bit_rate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FfprobeStream {
+    index: u32,
+    codec_name: Option<String>,
+    codec_type: Option<String>,
+    profile: Option<String>,
+    level: Option<i32>,
+    width: Option<u32>,
+    height: Option<u32>,
+    avg_frame_rate: Option<String>,
+    bit_rate: Option<String>,
+    pix_fmt: Option<String>,
+    color_transfer: Option<String>,
+    color_primaries: Option<String>,
+    channels: Option<u32>,
+    channel_layout: Option<String>,
+
+    #[serde(default)]
+    tags: HashMap<String, String>,
+
+    #[serde(default)]
+    disposition: HashMap<String, i32>,
+
+    #[serde(default)]
+    side_data_list: Vec<HashMap<String, serde_json::Value>>,
+}
+
+impl FfprobeStream {
+    /// Cover art in e.g. MKV files shows up as a video stream.
+    fn is_attached_pic(&self) -> bool {
+        self.disposition.get("attached_pic") == Some(&1)
+    }
+
+    fn hdr(&self) -> Option<Hdr> {
+        match self.color_transfer.as_deref() {
+            Some("smpte2084") => Some(Hdr::Pq),
+            Some("arib-std-b67") => Some(Hdr::Hlg),
+            _ => None,
+        }
+    }
+
+    fn is_dolby_vision(&self) -> bool {
+        self.side_data_list.iter().any(|side_data| {
+            side_data.get("side_data_type").and_then(|t| t.as_str())
+                == Some("DOVI configuration record")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const OUTPUT: &str = r#"{
+        "streams": [
+            {
+                "index": 0,
+                "codec_name": "hevc",
+                "codec_type": "video",
+                "profile": "Main 10",
+                "level": 153,
+                "width": 3840,
+                "height": 2160,
+                "avg_frame_rate": "24000/1001",
+                "pix_fmt": "yuv420p10le",
+                "color_transfer": "smpte2084",
+                "color_primaries": "bt2020",
+                "disposition": { "default": 1, "attached_pic": 0 }
+            },
+            {
+                "index": 1,
+                "codec_name": "dts",
+                "codec_type": "audio",
+                "channels": 6,
+                "channel_layout": "5.1(side)",
+                "bit_rate": "1509000",
+                "disposition": { "default": 1 },
+                "tags": { "language": "eng", "title": "Surround" }
+            },
+            {
+                "index": 2,
+                "codec_name": "hdmv_pgs_subtitle",
+                "codec_type": "subtitle",
+                "disposition": { "default": 0, "forced": 1 },
+                "tags": { "language": "dan" }
+            },
+            {
+                "index": 3,
+                "codec_name": "mjpeg",
+                "codec_type": "video",
+                "disposition": { "attached_pic": 1 }
+            }
+        ],
+        "format": {
+            "format_name": "matroska,webm",
+            "duration": "5400.123000",
+            "bit_rate": "40000000"
+        }
+    }"#;
+
+    #[test]
+    fn parses_ffprobe_output() {
+        let output: FfprobeOutput = serde_json::from_str(OUTPUT).unwrap();
+        let info = MediaInfo::from(output);
+
+        assert_eq!(info.container.as_deref(), Some("matroska,webm"));
+        assert_eq!(info.duration, Some(5400.123));
+        assert_eq!(info.bit_rate, Some(40_000_000));
+        assert_eq!(info.verdict, Verdict::Transcode);
+
+        assert_eq!(info.video.len(), 1);
+        let video = &info.video[0];
+        assert_eq!(video.codec.as_deref(), Some("hevc"));
+        assert_eq!((video.width, video.height), (Some(3840), Some(2160)));
+        assert_eq!(video.hdr, Some(Hdr::Pq));
+        assert!(!video.dolby_vision);
+        assert!((video.frame_rate.unwrap() - 23.976).abs() < 0.001);
+
+        assert_eq!(info.audio.len(), 1);
+        let audio = &info.audio[0];
+        assert_eq!(audio.language.as_deref(), Some("eng"));
+        assert_eq!(audio.title.as_deref(), Some("Surround"));
+        assert_eq!(audio.channel_layout.as_deref(), Some("5.1(side)"));
+        assert_eq!(audio.bit_rate, Some(1_509_000));
+        assert!(audio.default);
+
+        assert_eq!(info.subtitles.len(), 1);
+        let subtitle = &info.subtitles[0];
+        assert_eq!(subtitle.index, 2);
+        assert!(subtitle.forced);
+        assert!(subtitle.image_based);
+    }
+
+    #[test_case("mov,mp4,m4a,3gp,3g2,mj2", Some("h264"), Some("aac") => Verdict::DirectPlay; "when mp4 with h264 and aac")]
+    #[test_case("mov,mp4,m4a,3gp,3g2,mj2", Some("h264"), None => Verdict::DirectPlay; "when mp4 with h264 without audio")]
+    #[test_case("matroska,webm", Some("h264"), Some("aac") => Verdict::Remux; "when mkv with h264 and aac")]
+    #[test_case("matroska,webm", Some("h264"), Some("mp3") => Verdict::Remux; "when mkv with h264 and mp3")]
+    #[test_case("matroska,webm", Some("h264"), Some("dts") => Verdict::TranscodeAudio; "when mkv with h264 and dts")]
+    #[test_case("mov,mp4,m4a,3gp,3g2,mj2", Some("h264"), Some("ac3") => Verdict::TranscodeAudio; "when mp4 with h264 and ac3")]
+    #[test_case("matroska,webm", Some("hevc"), Some("aac") => Verdict::Transcode; "when mkv with hevc and aac")]
+    #[test_case("avi", Some("mpeg4"), Some("mp3") => Verdict::Transcode; "when avi with xvid and mp3")]
+    #[test_case("mov,mp4,m4a,3gp,3g2,mj2", None, Some("aac") => Verdict::Transcode; "when audio only")]
+    fn decides_verdict(container: &str, video: Option<&str>, audio: Option<&str>) -> Verdict {
+        let stream = |index: u32, codec_type: &str, codec: Option<&str>| {
+            codec.map(|codec| {
+                format!(
+                    r#"{{ "index": {}, "codec_type": "{}", "codec_name": "{}" }}"#,
+                    index, codec_type, codec
+                )
+            })
+        };
+
+        let streams: Vec<String> = [stream(0, "video", video), stream(1, "audio", audio)]
+            .into_iter()
+            .flatten()
+            .collect();
+
+        let json = format!(
+            r#"{{ "streams": [{}], "format": {{ "format_name": "{}" }} }}"#,
+            streams.join(","),
+            container
+        );
+
+        let output: FfprobeOutput = serde_json::from_str(&json).unwrap();
+        MediaInfo::from(output).verdict
+    }
+
+    #[tokio::test]
+    async fn refuses_files_outside_the_library() {
+        use rocket::{config::LogLevel, http::Status, local::asynchronous::Client, routes, Config};
+        use std::{env, fs, net::SocketAddr};
+
+        let dir = env::temp_dir().join("videocaster-probe-outside");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(dir.join("secret.mkv"), b"").unwrap();
+
+        let config = Config {
+            log_level: LogLevel::Off,
+            ..Config::debug_default()
+        };
+
+        let media = MediaRegistry::default();
+        let id = media.register(&dir.join("secret.mkv"));
+
+        let rocket = rocket::custom(config)
+            .mount("/", routes![handler])
+            .manage(Library::new(&[root]))
+            .manage(media)
+            .manage(ProfileConfig::default());
+
+        let client = Client::untracked(rocket).await.expect("rocket");
+        let local: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let response = client
+            .get(format!("/probe?id={}", id))
+            .remote(local)
+            .dispatch()
+            .await;
+
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+}
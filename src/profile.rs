@@ -0,0 +1,321 @@
+//! Capability profiles describe what a receiver can play, since e.g. a
+//! 1st-gen Chromecast, a Chromecast Ultra and a Google TV support different
+//! codecs, resolutions and HDR formats. Profiles are chosen per cast target
+//! and decide how a file is sent to it.
+use crate::probe::{AudioStream, Hdr, MediaInfo, Verdict, VideoStream};
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// The largest video the transcoder produces, see `transcode::encode_args`.
+const TRANSCODE_SIZE: (u32, u32) = (1920, 1080);
+
+/// What a receiver can play. Fields missing from the config are taken
+/// from the 1st-gen Chromecast.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub(crate) struct Profile {
+    /// Video codecs as named by ffprobe, e.g. "h264".
+    pub(crate) video_codecs: Vec<String>,
+
+    /// Audio codecs as named by ffprobe, e.g. "aac".
+    pub(crate) audio_codecs: Vec<String>,
+
+    /// Containers that can be played as is, as named by ffprobe, e.g. "mp4".
+    pub(crate) containers: Vec<String>,
+
+    pub(crate) max_width: u32,
+    pub(crate) max_height: u32,
+    pub(crate) max_frame_rate: f64,
+
+    /// Highest H.264 level times ten as reported by ffprobe, e.g. 41 for 4.1.
+    pub(crate) max_h264_level: i32,
+
+    /// HDR transfer characteristics the receiver can display.
+    pub(crate) hdr: Vec<Hdr>,
+
+    pub(crate) dolby_vision: bool,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            video_codecs: strings(&["h264"]),
+            audio_codecs: strings(&["aac", "mp3"]),
+            containers: strings(&["mp4"]),
+            max_width: 1920,
+            max_height: 1080,
+            max_frame_rate: 60.0,
+            max_h264_level: 42,
+            hdr: Vec::new(),
+            dolby_vision: false,
+        }
+    }
+}
+
+impl Profile {
+    /// Built-in profiles that can be used without configuring them.
+    fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "chromecast" => Some(Self::default()),
+            "chromecast-ultra" => Some(Self {
+                video_codecs: strings(&["h264", "hevc", "vp8", "vp9"]),
+                audio_codecs: strings(&["aac", "mp3", "ac3", "eac3"]),
+                max_width: 3840,
+                max_height: 2160,
+                max_h264_level: 51,
+                hdr: vec![Hdr::Pq, Hdr::Hlg],
+                dolby_vision: true,
+                ..Self::default()
+            }),
+            "google-tv" => Some(Self {
+                video_codecs: strings(&["h264", "hevc", "vp9", "av1"]),
+                audio_codecs: strings(&["aac", "mp3", "ac3", "eac3", "opus", "flac"]),
+                max_width: 3840,
+                max_height: 2160,
+                max_h264_level: 51,
+                hdr: vec![Hdr::Pq, Hdr::Hlg],
+                dolby_vision: true,
+                ..Self::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Decides how the first video stream and the `audio` stream of a file can be played.
+    pub(crate) fn verdict(&self, info: &MediaInfo, audio: Option<&AudioStream>) -> Verdict {
+        let video = match info.video.first() {
+            Some(video) if contains(&self.video_codecs, &video.codec) => video,
+            _ => return Verdict::Transcode,
+        };
+
+        if !self.displays(video) {
+            return Verdict::Transcode;
+        }
+
+        if self.exceeds_size(video) {
+            return Verdict::Downscale;
+        }
+
+        let compatible_audio = match audio {
+            Some(audio) => contains(&self.audio_codecs, &audio.codec),
+            None => true,
+        };
+
+        // ffprobe calls MP4 files "mov,mp4,m4a,3gp,3g2,mj2"
+        let container = matches!(
+            info.container.as_deref(),
+            Some(container) if container.split(',').any(|c| self.containers.iter().any(|s| s == c))
+        );
+
+        match (compatible_audio, container) {
+            (true, true) => Verdict::DirectPlay,
+            (true, false) => Verdict::Remux,
+            (false, _) => Verdict::TranscodeAudio,
+        }
+    }
+
+    /// Returns the largest size a transcoded `video` may have, if it must be scaled down.
+    pub(crate) fn transcode_size(&self, video: &VideoStream) -> Option<(u32, u32)> {
+        let size = (
+            self.max_width.min(TRANSCODE_SIZE.0),
+            self.max_height.min(TRANSCODE_SIZE.1),
+        );
+
+        match (video.width, video.height) {
+            (Some(width), Some(height)) if width > size.0 || height > size.1 => Some(size),
+            _ => None,
+        }
+    }
+
+    /// Whether HDR `video` must be converted to SDR for the receiver.
+    pub(crate) fn tone_maps(&self, video: &VideoStream) -> bool {
+        matches!(video.hdr, Some(hdr) if !self.hdr.contains(&hdr))
+    }
+
+    /// Whether the frame rate, level and dynamic range of `video` are supported.
+    fn displays(&self, video: &VideoStream) -> bool {
+        let frame_rate =
+            matches!(video.frame_rate, Some(rate) if rate > self.max_frame_rate + 0.01);
+
+        let level = video.codec.as_deref() == Some("h264")
+            && matches!(video.level, Some(level) if level > self.max_h264_level);
+
+        let hdr = self.tone_maps(video);
+        let dolby_vision = video.dolby_vision && !self.dolby_vision;
+
+        !(frame_rate || level || hdr || dolby_vision)
+    }
+
+    fn exceeds_size(&self, video: &VideoStream) -> bool {
+        matches!(video.width, Some(width) if width > self.max_width)
+            || matches!(video.height, Some(height) if height > self.max_height)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ProfileConfig {
+    /// Profiles by name, in addition to or replacing the built-in ones.
+    #[serde(default)]
+    pub(crate) profiles: HashMap<String, Profile>,
+
+    /// Profile names by cast target, i.e. the friendly name of the receiver.
+    #[serde(default)]
+    pub(crate) devices: HashMap<String, String>,
+
+    /// The profile of cast targets without one.
+    #[serde(default = "default_profile")]
+    pub(crate) default_profile: String,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> Self {
+        Self {
+            profiles: HashMap::new(),
+            devices: HashMap::new(),
+            default_profile: default_profile(),
+        }
+    }
+}
+
+impl ProfileConfig {
+    /// Returns the profile named `name` if given, or else the profile of
+    /// the cast target `device`, falling back to the default profile.
+    pub(crate) fn resolve(&self, name: Option<&str>, device: Option<&str>) -> Profile {
+        let name = name
+            .or_else(|| {
+                device
+                    .and_then(|device| self.devices.get(device))
+                    .map(String::as_str)
+            })
+            .unwrap_or(&self.default_profile);
+
+        self.profiles
+            .get(name)
+            .cloned()
+            .or_else(|| Profile::builtin(name))
+            .unwrap_or_else(|| {
+                warn!("unknown profile {}, using the default", name);
+                Profile::default()
+            })
+    }
+}
+
+fn default_profile() -> String {
+    "chromecast".to_owned()
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+fn contains(values: &[String], value: &Option<String>) -> bool {
+    matches!(value, Some(value) if values.contains(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn info(codec: &str, width: u32, hdr: Option<Hdr>, audio: &str, container: &str) -> MediaInfo {
+        MediaInfo {
+            container: Some(container.to_owned()),
+            duration: None,
+            bit_rate: None,
+            video: vec![VideoStream {
+                index: 0,
+                codec: Some(codec.to_owned()),
+                profile: None,
+                level: Some(41),
+                width: Some(width),
+                height: Some(width * 9 / 16),
+                frame_rate: Some(23.976),
+                bit_rate: None,
+                pixel_format: None,
+                color_transfer: None,
+                color_primaries: None,
+                hdr,
+                dolby_vision: false,
+            }],
+            audio: vec![AudioStream {
+                index: 1,
+                codec: Some(audio.to_owned()),
+                language: None,
+                title: None,
+                channels: Some(6),
+                channel_layout: None,
+                bit_rate: None,
+                default: true,
+            }],
+            subtitles: Vec::new(),
+            verdict: Verdict::Transcode,
+        }
+    }
+
+    const MP4: &str = "mov,mp4,m4a,3gp,3g2,mj2";
+    const MKV: &str = "matroska,webm";
+
+    #[test_case("chromecast", info("h264", 1920, None, "aac", MP4) => Verdict::DirectPlay; "when chromecast plays 1080p h264")]
+    #[test_case("chromecast", info("h264", 1920, None, "aac", MKV) => Verdict::Remux; "when chromecast plays mkv")]
+    #[test_case("chromecast", info("h264", 1920, None, "eac3", MKV) => Verdict::TranscodeAudio; "when chromecast plays eac3")]
+    #[test_case("chromecast", info("h264", 3840, None, "aac", MP4) => Verdict::Downscale; "when chromecast plays 4k h264")]
+    #[test_case("chromecast", info("hevc", 1920, None, "aac", MKV) => Verdict::Transcode; "when chromecast plays hevc")]
+    #[test_case("chromecast-ultra", info("hevc", 3840, Some(Hdr::Pq), "eac3", MKV) => Verdict::Remux; "when ultra plays 4k hdr hevc")]
+    #[test_case("chromecast-ultra", info("av1", 1920, None, "aac", MKV) => Verdict::Transcode; "when ultra plays av1")]
+    #[test_case("google-tv", info("av1", 3840, Some(Hdr::Hlg), "opus", MKV) => Verdict::Remux; "when google tv plays av1")]
+    fn decides_verdict(name: &str, info: MediaInfo) -> Verdict {
+        let profile = Profile::builtin(name).unwrap();
+        profile.verdict(&info, info.audio.first())
+    }
+
+    #[test]
+    fn transcodes_hdr_for_sdr_receivers() {
+        let mut profile = Profile::builtin("chromecast-ultra").unwrap();
+        profile.hdr = Vec::new();
+        let info = info("hevc", 3840, Some(Hdr::Pq), "aac", MP4);
+        assert_eq!(profile.verdict(&info, None), Verdict::Transcode);
+        assert!(profile.tone_maps(&info.video[0]));
+    }
+
+    #[test]
+    fn transcodes_high_levels() {
+        let mut info = info("h264", 1920, None, "aac", MP4);
+        info.video[0].level = Some(51);
+        assert_eq!(Profile::default().verdict(&info, None), Verdict::Transcode);
+    }
+
+    #[test_case("chromecast", 3840 => Some((1920, 1080)); "when 4k on chromecast")]
+    #[test_case("chromecast", 1280 => None; "when 720p on chromecast")]
+    #[test_case("google-tv", 3840 => Some((1920, 1080)); "when 4k on google tv")]
+    fn limits_transcode_size(name: &str, width: u32) -> Option<(u32, u32)> {
+        let info = info("hevc", width, None, "aac", MKV);
+        Profile::builtin(name)
+            .unwrap()
+            .transcode_size(&info.video[0])
+    }
+
+    #[test_case(Some("google-tv"), Some("Bedroom") => "google-tv"; "when name is given")]
+    #[test_case(None, Some("Bedroom") => "mine"; "when device has a profile")]
+    #[test_case(None, Some("Kitchen") => "chromecast"; "when device has no profile")]
+    #[test_case(Some("unknown"), None => "chromecast"; "when name is unknown")]
+    fn resolves(name: Option<&str>, device: Option<&str>) -> &'static str {
+        let mine = Profile {
+            max_width: 1280,
+            ..Profile::default()
+        };
+
+        let config = ProfileConfig {
+            profiles: HashMap::from([("mine".to_owned(), mine.clone())]),
+            devices: HashMap::from([("Bedroom".to_owned(), "mine".to_owned())]),
+            ..ProfileConfig::default()
+        };
+
+        match config.resolve(name, device) {
+            profile if profile == mine => "mine",
+            profile if profile == Profile::builtin("google-tv").unwrap() => "google-tv",
+            profile if profile == Profile::default() => "chromecast",
+            _ => "other",
+        }
+    }
+}
@@ -0,0 +1,897 @@
+//! Settings are the typed part of `Videocaster.toml` that is shared by most
+//! of the server. The file has a version, so files written by older
+//! versions are migrated before they are read. Invalid settings stop the
+//! server with an error naming the setting instead of falling back silently.
+//!
+//! Settings can be changed with `PUT /settings` or by editing the file and
+//! are applied without a restart. Rocket's own settings, e.g. the port,
+//! are only read on start. Environment variables override the file while
+//! the server runs, but are never written to it.
+use crate::{app_result::AppResult, auth::Paired, ffmpeg, library::Library};
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn, LevelFilter};
+use rocket::{
+    figment::{
+        self,
+        providers::{Env, Format, Toml},
+        Figment,
+    },
+    get,
+    http::Status,
+    put,
+    serde::json::Json,
+    Config, State,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tokio::sync::Mutex as AsyncMutex;
+use toml::{value::Table, Value};
+
+const ENV_PREFIX: &str = "VIDEOCASTER_";
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Version of the config file written by this version of Videocaster.
+pub(crate) const SETTINGS_VERSION: u32 = 2;
+
+/// Migrations by the version they upgrade from, starting with version 1.
+const MIGRATIONS: [fn(&mut Table); 1] = [v1_to_v2];
+
+/// The keys of `Settings` in the config file, which are replaced when saving.
+const SETTINGS_KEYS: [&str; 7] = [
+    "version",
+    "library_roots",
+    "ffmpeg_path",
+    "subtitle_languages",
+    "browser",
+    "browser_args",
+    "logging",
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct Settings {
+    /// Version of the config file, files without one are version 1.
+    pub(crate) version: u32,
+
+    /// Directories with videos. The home directory is used if none are given.
+    pub(crate) library_roots: Vec<PathBuf>,
+
+    /// The ffmpeg executable, ffprobe is expected next to it.
+    /// The ones on the PATH are used if none is given.
+    pub(crate) ffmpeg_path: Option<PathBuf>,
+
+    /// Languages subtitles are searched in as ISO 639-2 codes, e.g. "eng".
+    pub(crate) subtitle_languages: Vec<String>,
+
+    /// The browser opened on start, a name on the PATH or a path. The
+    /// first Chromium-family browser found is used if none is given.
+    pub(crate) browser: Option<PathBuf>,
+
+    /// Extra arguments for the browser, e.g. "--start-fullscreen".
+    pub(crate) browser_args: Vec<String>,
+
+    /// The log level, named so it doesn't clash with Rocket's `log_level`.
+    pub(crate) logging: LogLevel,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: SETTINGS_VERSION,
+            library_roots: Vec::new(),
+            ffmpeg_path: None,
+            subtitle_languages: vec!["eng".to_owned()],
+            browser: None,
+            browser_args: Vec::new(),
+            logging: LogLevel::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    #[default]
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum SettingsError {
+    #[error("invalid settings: {0}")]
+    Extract(Box<figment::Error>),
+
+    #[error("version {0} is newer than the supported version {}", SETTINGS_VERSION)]
+    UnsupportedVersion(u32),
+
+    #[error("library root {} must be an absolute path", .0.display())]
+    RelativeLibraryRoot(PathBuf),
+
+    #[error("ffmpeg_path {} is not a file", .0.display())]
+    FfmpegNotFound(PathBuf),
+
+    #[error("subtitle language {0:?} is not an ISO 639-2 code, e.g. \"eng\"")]
+    SubtitleLanguage(String),
+
+    #[error("browser must not be empty")]
+    EmptyBrowser,
+
+    #[error("failed to save settings: {0:#}")]
+    Save(anyhow::Error),
+}
+
+impl<T: Serialize> From<SettingsError> for AppResult<T> {
+    fn from(err: SettingsError) -> Self {
+        let status = match err {
+            SettingsError::Save(_) => Status::InternalServerError,
+            _ => Status::BadRequest,
+        };
+
+        AppResult::error(err).with_status(status)
+    }
+}
+
+impl From<figment::Error> for SettingsError {
+    fn from(err: figment::Error) -> Self {
+        Self::Extract(Box::new(err))
+    }
+}
+
+impl Settings {
+    /// Extracts the settings from `figment` and validates them.
+    pub(crate) fn extract(figment: &Figment) -> Result<Self, SettingsError> {
+        let settings = figment.extract::<Self>()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), SettingsError> {
+        if self.version > SETTINGS_VERSION {
+            return Err(SettingsError::UnsupportedVersion(self.version));
+        }
+
+        if let Some(root) = self.library_roots.iter().find(|root| !root.is_absolute()) {
+            return Err(SettingsError::RelativeLibraryRoot(root.clone()));
+        }
+
+        if let Some(path) = self.ffmpeg_path.as_ref().filter(|path| !path.is_file()) {
+            return Err(SettingsError::FfmpegNotFound(path.clone()));
+        }
+
+        if let Some(language) = self
+            .subtitle_languages
+            .iter()
+            .find(|language| !is_language_code(language))
+        {
+            return Err(SettingsError::SubtitleLanguage(language.clone()));
+        }
+
+        if matches!(&self.browser, Some(browser) if browser.as_os_str().is_empty()) {
+            return Err(SettingsError::EmptyBrowser);
+        }
+
+        Ok(())
+    }
+}
+
+/// The settings in the config file, without environment variables.
+#[get("/settings")]
+pub(crate) async fn handler(_paired: Paired, store: &State<SettingsStore>) -> AppResult<Settings> {
+    match store.persisted() {
+        Ok(settings) => AppResult::success(settings),
+        Err(err) => err.into(),
+    }
+}
+
+/// Replaces the settings, missing settings are reset to their defaults.
+#[put("/settings", data = "<settings>")]
+pub(crate) async fn update(
+    _paired: Paired,
+    settings: Json<Settings>,
+    store: &State<SettingsStore>,
+) -> AppResult<Settings> {
+    match store.save(settings.into_inner()).await {
+        Ok(settings) => AppResult::success(settings),
+        Err(err) => err.into(),
+    }
+}
+
+/// The config of the server, i.e. Rocket's defaults, the `[default]`
+/// profile of the config file and environment variables with our prefix.
+pub(crate) fn figment(config_path: &Path) -> Figment {
+    Figment::from(Config::default())
+        .merge(Toml::file(config_path).nested())
+        .merge(Env::prefixed(ENV_PREFIX).global())
+}
+
+/// Only the `[default]` profile of the config file, i.e. the settings
+/// that are saved.
+fn file_figment(config_path: &Path) -> Figment {
+    Figment::from(Toml::file(config_path).nested())
+}
+
+/// Migrates the config file and loads the settings from it.
+pub(crate) async fn load(config_path: &Path) -> Result<Settings> {
+    migrate_file(config_path).await?;
+    Ok(Settings::extract(&figment(config_path))?)
+}
+
+/// The current settings, shared by everything that applies them live.
+#[derive(Clone)]
+pub(crate) struct SettingsStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    settings: RwLock<Settings>,
+    library: Library,
+
+    /// When the config file was last read or written, locked while it is.
+    modified: AsyncMutex<Option<SystemTime>>,
+}
+
+impl SettingsStore {
+    /// Applies the `settings` that aren't kept in managed state, the
+    /// `library` is expected to use the library roots already.
+    pub(crate) fn new(path: &Path, settings: Settings, library: Library) -> Self {
+        apply_globals(&settings);
+
+        Self {
+            inner: Arc::new(Inner {
+                path: path.to_path_buf(),
+                settings: RwLock::new(settings),
+                library,
+                modified: AsyncMutex::new(modified(path)),
+            }),
+        }
+    }
+
+    /// The settings in effect, i.e. with environment variables.
+    pub(crate) fn get(&self) -> Settings {
+        self.inner.settings.read().expect("settings").clone()
+    }
+
+    fn persisted(&self) -> Result<Settings, SettingsError> {
+        Settings::extract(&file_figment(&self.inner.path))
+    }
+
+    /// Validates the `settings`, writes them to the config file and applies
+    /// them along with environment variables. Returns what was written.
+    pub(crate) async fn save(&self, settings: Settings) -> Result<Settings, SettingsError> {
+        let settings = Settings {
+            version: SETTINGS_VERSION,
+            ..settings
+        };
+
+        settings.validate()?;
+
+        let mut modified = self.inner.modified.lock().await;
+        write(&self.inner.path, &settings)
+            .await
+            .map_err(SettingsError::Save)?;
+        *modified = self::modified(&self.inner.path);
+
+        info!("saved settings to {}", self.inner.path.display());
+        self.apply(Settings::extract(&figment(&self.inner.path))?);
+        Ok(settings)
+    }
+
+    /// Reloads the settings whenever the config file is changed, e.g. by hand.
+    pub(crate) async fn watch(self) {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = self.reload_if_changed().await {
+                error!("failed to reload settings: {:#}", err);
+            }
+        }
+    }
+
+    async fn reload_if_changed(&self) -> Result<()> {
+        let mut modified = self.inner.modified.lock().await;
+        let current = self::modified(&self.inner.path);
+
+        if current == *modified {
+            return Ok(());
+        }
+
+        // an invalid file is only reported once, not on every check
+        *modified = current;
+        info!("{} changed, reloading settings", self.inner.path.display());
+        let settings = load(&self.inner.path).await?;
+        *modified = self::modified(&self.inner.path);
+        self.apply(settings);
+        Ok(())
+    }
+
+    fn apply(&self, settings: Settings) {
+        apply_globals(&settings);
+        self.inner.library.set_roots(&settings.library_roots);
+        *self.inner.settings.write().expect("settings") = settings;
+    }
+}
+
+/// Applies the settings that are read by free functions, not from managed state.
+pub(crate) fn apply_globals(settings: &Settings) {
+    log::set_max_level(settings.logging.into());
+    ffmpeg::set_path(settings.ffmpeg_path.clone());
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    path.metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Replaces the settings in the config file, keeping everything else. The
+/// file is edited in place if possible, otherwise it is rewritten, which
+/// loses comments, so the old file is kept next to it.
+async fn write(path: &Path, settings: &Settings) -> Result<()> {
+    let source = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    let mut config = source
+        .parse::<Value>()
+        .with_context(|| format!("{} is not valid TOML", path.display()))?;
+
+    replace_settings(&mut config, settings)?;
+
+    // the edit must mean the same as replacing the settings in the parsed file
+    let edited = edit_settings(&source, settings)?;
+
+    if edited.parse::<Value>().ok().as_ref() == Some(&config) {
+        tokio::fs::write(path, edited).await?;
+        return Ok(());
+    }
+
+    let backup = path.with_extension("backup.toml");
+    tokio::fs::write(&backup, source).await?;
+    tokio::fs::write(path, toml::to_string(&config)?).await?;
+
+    warn!(
+        "rewrote {} to save settings, the old file is {}",
+        path.display(),
+        backup.display()
+    );
+
+    Ok(())
+}
+
+/// Replaces the settings in the `[default]` table of the config `source`
+/// line by line, so comments and formatting are kept.
+fn edit_settings(source: &str, settings: &Settings) -> Result<String> {
+    let values = match Value::try_from(settings)? {
+        Value::Table(values) => values,
+        _ => bail!("settings are not a table"),
+    };
+
+    Ok(edit_default(source, &SETTINGS_KEYS, &values))
+}
+
+/// Sets the `keys` of the `[default]` table of the config `source` to
+/// their `values` line by line, removing the keys without a value. Keys are
+/// replaced where they are and new ones are added after the last key.
+fn edit_default(source: &str, keys: &[&str], values: &Table) -> String {
+    let lines = source.lines().collect::<Vec<_>>();
+
+    let header = match lines.iter().position(|line| line.trim() == "[default]") {
+        Some(header) => header,
+        None => {
+            let mut edited = format!("{}\n[default]\n", source.trim_end());
+            for key in keys.iter().filter(|key| values.contains_key(**key)) {
+                edited.push_str(&format!("{} = {}\n", key, values[*key]));
+            }
+            return edited;
+        }
+    };
+
+    let end = lines[header + 1..]
+        .iter()
+        .position(|line| line.trim_start().starts_with('['))
+        .map_or(lines.len(), |end| header + 1 + end);
+
+    let mut edited = lines[..=header]
+        .iter()
+        .map(|line| line.to_string())
+        .collect::<Vec<_>>();
+    let mut written = Vec::new();
+    let mut last_key = edited.len();
+    let mut index = header + 1;
+
+    while index < end {
+        let line = lines[index];
+        index += 1;
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) if !line.trim_start().starts_with('#') => {
+                (key.trim().trim_matches('"'), value)
+            }
+            _ => {
+                edited.push(line.to_owned());
+                continue;
+            }
+        };
+
+        // arrays may continue on the following lines
+        let first = index - 1;
+        let mut depth = bracket_depth(value);
+        while depth > 0 && index < end {
+            depth += bracket_depth(lines[index]);
+            index += 1;
+        }
+
+        if !keys.contains(&key) {
+            edited.extend(lines[first..index].iter().map(|line| line.to_string()));
+            last_key = edited.len();
+            continue;
+        }
+
+        if let Some(value) = values.get(key) {
+            edited.push(format!("{} = {}", key, value));
+            written.push(key);
+            last_key = edited.len();
+        }
+    }
+
+    let missing = keys
+        .iter()
+        .filter(|key| values.contains_key(**key) && !written.contains(key))
+        .map(|key| format!("{} = {}", key, values[*key]))
+        .collect::<Vec<_>>();
+
+    edited.splice(last_key..last_key, missing);
+    edited.extend(lines[end..].iter().map(|line| line.to_string()));
+
+    let mut edited = edited.join("\n");
+    edited.push('\n');
+    edited
+}
+
+/// How many more brackets a line of TOML opens than it closes, ignoring
+/// strings and comments.
+fn bracket_depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+
+    for c in line.chars() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                '#' => break,
+                _ => {}
+            },
+        }
+    }
+
+    depth
+}
+
+fn replace_settings(config: &mut Value, settings: &Settings) -> Result<()> {
+    let profile = config
+        .as_table_mut()
+        .map(|config| {
+            config
+                .entry("default")
+                .or_insert_with(|| Table::new().into())
+        })
+        .and_then(Value::as_table_mut)
+        .context("[default] is not a table")?;
+
+    for key in SETTINGS_KEYS {
+        profile.remove(key);
+    }
+
+    // settings that are not set, e.g. `browser`, are left out
+    if let Value::Table(values) = Value::try_from(settings)? {
+        profile.extend(values);
+    }
+
+    Ok(())
+}
+
+fn is_language_code(language: &str) -> bool {
+    language.len() == 3 && language.bytes().all(|b| b.is_ascii_lowercase())
+}
+
+/// Migrates the config file at `path` to the current version. The keys
+/// that changed are edited in place if possible, otherwise the file is
+/// rewritten, which loses comments, so the old file is kept next to it.
+pub(crate) async fn migrate_file(path: &Path) -> Result<()> {
+    let source = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    let mut config = source
+        .parse::<Value>()
+        .with_context(|| format!("{} is not valid TOML", path.display()))?;
+
+    let original = config.clone();
+
+    let from = match migrate(&mut config)? {
+        Some(from) => from,
+        None => return Ok(()),
+    };
+
+    let edited = edit_migrated(&source, &original, &config);
+
+    if edited.parse::<Value>().ok().as_ref() == Some(&config) {
+        tokio::fs::write(path, edited).await?;
+        info!(
+            "migrated {} from version {} to {}",
+            path.display(),
+            from,
+            SETTINGS_VERSION
+        );
+        return Ok(());
+    }
+
+    let backup = path.with_extension(format!("v{}.toml", from));
+    tokio::fs::write(&backup, source).await?;
+    tokio::fs::write(path, toml::to_string(&config)?).await?;
+
+    info!(
+        "migrated {} from version {} to {}, the old file is {}",
+        path.display(),
+        from,
+        SETTINGS_VERSION,
+        backup.display()
+    );
+
+    Ok(())
+}
+
+/// Edits the keys of the `[default]` profile that differ between the
+/// `original` and `migrated` config in the config `source`.
+fn edit_migrated(source: &str, original: &Value, migrated: &Value) -> String {
+    let profile = |config: &Value| {
+        config
+            .get("default")
+            .and_then(Value::as_table)
+            .cloned()
+            .unwrap_or_default()
+    };
+
+    let (original, migrated) = (profile(original), profile(migrated));
+    let mut keys = original
+        .keys()
+        .chain(migrated.keys())
+        .filter(|key| original.get(*key) != migrated.get(*key))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    keys.sort_unstable();
+    keys.dedup();
+
+    edit_default(source, &keys, &migrated)
+}
+
+/// Migrates the `[default]` profile of `config` to the current version and
+/// returns the version it had, if it was older.
+fn migrate(config: &mut Value) -> Result<Option<u32>, SettingsError> {
+    let profile = match config
+        .as_table_mut()
+        .map(|config| {
+            config
+                .entry("default")
+                .or_insert_with(|| Table::new().into())
+        })
+        .and_then(Value::as_table_mut)
+    {
+        Some(profile) => profile,
+        None => return Ok(None),
+    };
+
+    let from = match profile.get("version").and_then(Value::as_integer) {
+        Some(version) => u32::try_from(version).unwrap_or(u32::MAX).max(1),
+        None => 1,
+    };
+
+    if from > SETTINGS_VERSION {
+        return Err(SettingsError::UnsupportedVersion(from));
+    }
+
+    if from == SETTINGS_VERSION {
+        return Ok(None);
+    }
+
+    for version in from..SETTINGS_VERSION {
+        MIGRATIONS[version as usize - 1](profile);
+    }
+
+    let version = Value::Integer(i64::from(SETTINGS_VERSION));
+    profile.insert("version".to_owned(), version);
+
+    Ok(Some(from))
+}
+
+/// Version 2 only adds settings, which have defaults, so files from
+/// before there were versions are kept as they are.
+fn v1_to_v2(_profile: &mut Table) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::figment::providers::{Format, Toml};
+    use test_case::test_case;
+
+    fn extract(toml: &str) -> Result<Settings, SettingsError> {
+        Settings::extract(&Figment::from(Toml::string(toml).nested()))
+    }
+
+    #[test]
+    fn uses_defaults_for_missing_settings() {
+        let settings = extract("[default]\nport = 33671").unwrap();
+        assert_eq!(settings, Settings::default());
+    }
+
+    #[test]
+    fn extracts_settings() {
+        let settings = extract(
+            r#"
+            [default]
+            library_roots = ["/videos"]
+            subtitle_languages = ["dan", "eng"]
+            browser = "chromium"
+            logging = "warn"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.library_roots, vec![PathBuf::from("/videos")]);
+        assert_eq!(settings.subtitle_languages, vec!["dan", "eng"]);
+        assert_eq!(settings.browser, Some(PathBuf::from("chromium")));
+        assert_eq!(settings.logging, LogLevel::Warn);
+    }
+
+    #[test_case("library_roots = [\"videos\"]" => "library root videos must be an absolute path")]
+    #[test_case("ffmpeg_path = \"/no/such/ffmpeg\"" => "ffmpeg_path /no/such/ffmpeg is not a file")]
+    #[test_case("subtitle_languages = [\"en\"]" => "subtitle language \"en\" is not an ISO 639-2 code, e.g. \"eng\"")]
+    #[test_case("browser = \"\"" => "browser must not be empty")]
+    #[test_case("version = 3" => "version 3 is newer than the supported version 2")]
+    fn rejects_invalid_settings(setting: &str) -> String {
+        extract(&format!("[default]\n{}", setting))
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn rejects_unknown_log_levels() {
+        let err = extract("[default]\nlogging = \"loud\"").unwrap_err();
+        assert!(matches!(err, SettingsError::Extract(_)));
+    }
+
+    #[test]
+    fn migrates_files_without_a_version() {
+        let mut config = "[default]\nport = 1234".parse::<Value>().unwrap();
+
+        assert_eq!(migrate(&mut config).unwrap(), Some(1));
+        assert_eq!(config["default"]["version"].as_integer(), Some(2));
+        assert_eq!(config["default"]["port"].as_integer(), Some(1234));
+        assert_eq!(migrate(&mut config).unwrap(), None);
+    }
+
+    #[test]
+    fn migrates_files_in_place() {
+        let source = "# my config\n[default]\n# where my videos are\nlibrary_roots = [\"/videos\"]\nport = 1234\n\n[debug]\nport = 8000\n";
+        let original = source.parse::<Value>().unwrap();
+        let mut config = original.clone();
+        migrate(&mut config).unwrap();
+
+        let edited = edit_migrated(source, &original, &config);
+        assert_eq!(
+            edited,
+            "# my config\n[default]\n# where my videos are\nlibrary_roots = [\"/videos\"]\nport = 1234\nversion = 2\n\n[debug]\nport = 8000\n"
+        );
+        assert_eq!(edited.parse::<Value>().unwrap(), config);
+    }
+
+    #[tokio::test]
+    async fn migrates_files_without_rewriting_them() {
+        let dir = std::env::temp_dir().join("videocaster-migrate-test");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("Videocaster.toml");
+        let source = "[default]\n# shown in the ui\nsubtitle_languages = [\n  \"eng\",\n]\n";
+        tokio::fs::write(&path, source).await.unwrap();
+
+        migrate_file(&path).await.unwrap();
+
+        let migrated = tokio::fs::read_to_string(&path).await.unwrap();
+        assert_eq!(migrated, format!("{}version = 2\n", source));
+        assert!(!path.with_extension("v1.toml").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_settings_and_keeps_other_keys() {
+        let mut config =
+            "[default]\nport = 1234\nbrowser = \"brave\"\n[default.devices]\nTV = \"google-tv\""
+                .parse::<Value>()
+                .unwrap();
+
+        let settings = Settings {
+            library_roots: vec![PathBuf::from("/videos")],
+            ..Settings::default()
+        };
+
+        replace_settings(&mut config, &settings).unwrap();
+        let profile = &config["default"];
+
+        assert_eq!(profile["port"].as_integer(), Some(1234));
+        assert_eq!(profile["devices"]["TV"].as_str(), Some("google-tv"));
+        assert_eq!(profile["library_roots"][0].as_str(), Some("/videos"));
+        assert_eq!(profile.get("browser"), None);
+        assert_eq!(
+            extract(&toml::to_string(&config).unwrap()).unwrap(),
+            settings
+        );
+    }
+
+    #[test]
+    fn edits_settings_in_place() {
+        let source = r#"# Videocaster
+[default]
+# where the videos are
+library_roots = [
+    "/old", # the old disk
+]
+port = 1234
+browser = "brave"
+
+[default.devices]
+TV = "google-tv"
+"#;
+
+        let settings = Settings {
+            library_roots: vec![PathBuf::from("/videos")],
+            logging: LogLevel::Warn,
+            ..Settings::default()
+        };
+
+        let edited = edit_settings(source, &settings).unwrap();
+
+        assert!(edited.starts_with("# Videocaster\n[default]\n# where the videos are\n"));
+        assert!(edited.contains("library_roots = [\"/videos\"]\nport = 1234\n"));
+        assert!(edited.contains("logging = \"warn\"\n\n[default.devices]\nTV = "));
+        assert!(!edited.contains("brave"));
+        assert_eq!(extract(&edited).unwrap(), settings);
+    }
+
+    #[test_case("a = [\"[\", # ]" => 1; "when brackets are in strings and comments")]
+    #[test_case("a = [[1, 2], [3]]" => 0; "when nested")]
+    #[test_case("a = 'C:\\' ]" => -1; "when a literal string ends with a backslash")]
+    fn counts_brackets(line: &str) -> i32 {
+        bracket_depth(line)
+    }
+
+    #[tokio::test]
+    async fn rewrites_files_it_cannot_edit() {
+        let dir = std::env::temp_dir().join("videocaster-settings-rewrite");
+        let path = dir.join("Videocaster.toml");
+        let source = "[default]\nbrowser = \"\"\"\nbrave\"\"\"\n";
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, source).unwrap();
+
+        write(&path, &Settings::default()).await.unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(extract(&written).unwrap(), Settings::default());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("Videocaster.backup.toml")).unwrap(),
+            source
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn settings_keys_cover_all_settings() {
+        let settings = Settings {
+            ffmpeg_path: Some(PathBuf::from("/usr/bin/ffmpeg")),
+            browser: Some(PathBuf::from("chromium")),
+            ..Settings::default()
+        };
+
+        let values = Value::try_from(&settings).unwrap();
+        let mut keys = values.as_table().unwrap().keys().collect::<Vec<_>>();
+        let mut expected = SETTINGS_KEYS.to_vec();
+        keys.sort();
+        expected.sort_unstable();
+
+        assert_eq!(keys, expected);
+    }
+
+    #[tokio::test]
+    async fn saves_and_reloads_settings() {
+        let dir = std::env::temp_dir().join("videocaster-settings");
+        let path = dir.join("Videocaster.toml");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&path, "[default]\nversion = 2\nport = 1234\n").unwrap();
+
+        let library = Library::new(std::slice::from_ref(&dir));
+        let store = SettingsStore::new(&path, Settings::default(), library.clone());
+        let settings = Settings {
+            library_roots: vec![dir.join("videos")],
+            ..Settings::default()
+        };
+
+        std::fs::create_dir_all(dir.join("videos")).unwrap();
+        store.save(settings.clone()).await.unwrap();
+        assert_eq!(store.get(), settings);
+        assert_eq!(store.persisted().unwrap(), settings);
+        assert!(library.home().unwrap().ends_with("videos"));
+        assert!(std::fs::read_to_string(&path)
+            .unwrap()
+            .contains("port = 1234"));
+
+        let edited = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("\"eng\"", "\"dan\"");
+        std::fs::write(&path, edited).unwrap();
+        store.reload_if_changed().await.unwrap();
+        assert_eq!(store.get().subtitle_languages, vec!["dan"]);
+
+        let invalid = Settings {
+            subtitle_languages: vec!["danish".to_owned()],
+            ..Settings::default()
+        };
+        assert!(matches!(
+            store.save(invalid).await,
+            Err(SettingsError::SubtitleLanguage(_))
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn release_config_is_current() {
+        let release = include_str!("../Release.toml");
+        let mut config = release.parse::<Value>().unwrap();
+
+        assert_eq!(extract(release).unwrap(), Settings::default());
+        assert_eq!(migrate(&mut config).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_migrating_newer_files() {
+        let mut config = "[default]\nversion = 3".parse::<Value>().unwrap();
+        assert!(matches!(
+            migrate(&mut config),
+            Err(SettingsError::UnsupportedVersion(3))
+        ));
+    }
+}
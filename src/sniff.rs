@@ -0,0 +1,178 @@
+//! Detects the container of media files from their first bytes, since
+//! extensions may be missing or unknown, e.g. `.mkv` to Rocket.
+use anyhow::{Context, Error};
+use rocket::http::ContentType;
+use std::path::Path;
+use tokio::{fs::File, io::AsyncReadExt};
+
+/// How many bytes are read from the start of a file to detect its container.
+pub(crate) const SNIFF_LEN: usize = 1024;
+
+const EBML_MAGIC: [u8; 4] = [0x1a, 0x45, 0xdf, 0xa3];
+const EBML_DOC_TYPE: [u8; 2] = [0x42, 0x82];
+const TS_PACKET_LEN: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+/// Major brands of ISO base media files that hold video. Others, e.g. HEIC,
+/// AVIF or M4A, use the same boxes for images and audio.
+const VIDEO_BRANDS: [&[u8; 4]; 12] = [
+    b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"M4V ", b"M4VP",
+    b"dash", b"qt  ",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Container {
+    Matroska,
+    WebM,
+    Mp4,
+    Avi,
+
+    /// MPEG transport stream, including Blu-ray M2TS with 4-byte timecodes.
+    MpegTs,
+}
+
+impl Container {
+    /// Detects the container from the first bytes of a file.
+    pub(crate) fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&EBML_MAGIC) {
+            // WebM is Matroska with a different doc type
+            return match doc_type(bytes) {
+                Some(b"webm") => Some(Container::WebM),
+                _ => Some(Container::Matroska),
+            };
+        }
+
+        if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+            let brand = &bytes[8..12];
+
+            // images and audio in the same boxes aren't video
+            return if VIDEO_BRANDS.iter().any(|video| brand == *video) {
+                Some(Container::Mp4)
+            } else {
+                None
+            };
+        }
+
+        if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"AVI " {
+            return Some(Container::Avi);
+        }
+
+        if is_transport_stream(bytes, 0) || is_transport_stream(bytes, 4) {
+            return Some(Container::MpegTs);
+        }
+
+        None
+    }
+
+    pub(crate) fn content_type(&self) -> ContentType {
+        match self {
+            Container::Matroska => ContentType::new("video", "x-matroska"),
+            Container::WebM => ContentType::new("video", "webm"),
+            Container::Mp4 => ContentType::MP4,
+            Container::Avi => ContentType::new("video", "x-msvideo"),
+            Container::MpegTs => ContentType::new("video", "mp2t"),
+        }
+    }
+}
+
+/// Reads the start of the file at `path` and detects its container.
+pub(crate) async fn sniff(path: &Path) -> Result<Option<Container>, Error> {
+    let mut file = File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+
+    let mut bytes = Vec::with_capacity(SNIFF_LEN);
+    (&mut file)
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut bytes)
+        .await?;
+    Ok(Container::detect(&bytes))
+}
+
+/// Finds the DocType element in the EBML header and returns its value.
+fn doc_type(bytes: &[u8]) -> Option<&[u8]> {
+    let start = bytes
+        .windows(EBML_DOC_TYPE.len())
+        .position(|window| window == EBML_DOC_TYPE)?
+        + EBML_DOC_TYPE.len();
+
+    // the size is a variable length integer, its length is given by the leading zeros
+    let first = *bytes.get(start)?;
+    let len = first.leading_zeros() as usize + 1;
+
+    if len > 8 {
+        return None;
+    }
+
+    let mut size = u64::from(first) & (0xff >> len);
+
+    for byte in bytes.get(start + 1..start + len)? {
+        size = (size << 8) | u64::from(*byte);
+    }
+
+    let value = start + len;
+    bytes.get(value..value + size as usize)
+}
+
+/// Whether packets start with the sync byte at `offset` in three consecutive packets.
+fn is_transport_stream(bytes: &[u8], offset: usize) -> bool {
+    let packet_len = TS_PACKET_LEN + offset;
+
+    (0..3).all(|i| bytes.get(offset + i * packet_len) == Some(&TS_SYNC_BYTE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn ebml(doc_type: &[u8]) -> Vec<u8> {
+        let mut bytes = EBML_MAGIC.to_vec();
+        // header size, EBMLVersion 1
+        bytes.extend_from_slice(&[0xa3, 0x42, 0x86, 0x81, 0x01]);
+        bytes.extend_from_slice(&EBML_DOC_TYPE);
+        bytes.push(0x80 | doc_type.len() as u8);
+        bytes.extend_from_slice(doc_type);
+        bytes
+    }
+
+    fn transport_stream(packet_len: usize, offset: usize) -> Vec<u8> {
+        let mut bytes = vec![0; packet_len * 3];
+
+        for i in 0..3 {
+            bytes[offset + i * packet_len] = TS_SYNC_BYTE;
+        }
+
+        bytes
+    }
+
+    #[test_case(&ebml(b"matroska") => Some(Container::Matroska); "when matroska")]
+    #[test_case(&ebml(b"webm") => Some(Container::WebM); "when webm")]
+    #[test_case(b"\x00\x00\x00\x20ftypisom\x00\x00\x02\x00" => Some(Container::Mp4); "when mp4")]
+    #[test_case(b"\x00\x00\x00\x18ftypmp42\x00\x00\x00\x00" => Some(Container::Mp4); "when mp42")]
+    #[test_case(b"\x00\x00\x00\x14ftypqt  \x00\x00\x02\x00" => Some(Container::Mp4); "when quicktime")]
+    #[test_case(b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00" => None; "when heic")]
+    #[test_case(b"\x00\x00\x00\x1cftypavif\x00\x00\x00\x00" => None; "when avif")]
+    #[test_case(b"\x00\x00\x00\x18ftypmif1\x00\x00\x00\x00" => None; "when mif1")]
+    #[test_case(b"\x00\x00\x00\x20ftypM4A \x00\x00\x02\x00" => None; "when m4a")]
+    #[test_case(b"\x00\x00\x00\x20ftyp" => None; "when truncated ftyp")]
+    #[test_case(b"RIFF\x10\x00\x00\x00AVI LIST" => Some(Container::Avi); "when avi")]
+    #[test_case(b"RIFF\x10\x00\x00\x00WAVEfmt " => None; "when wav")]
+    #[test_case(&transport_stream(188, 0) => Some(Container::MpegTs); "when mpeg-ts")]
+    #[test_case(&transport_stream(192, 4) => Some(Container::MpegTs); "when m2ts")]
+    #[test_case(b"\x89PNG\r\n\x1a\n" => None; "when png")]
+    #[test_case(b"" => None; "when empty")]
+    fn detects(bytes: &[u8]) -> Option<Container> {
+        Container::detect(bytes)
+    }
+
+    #[test]
+    fn reads_long_doc_type_size() {
+        let mut bytes = EBML_MAGIC.to_vec();
+        bytes.extend_from_slice(&EBML_DOC_TYPE);
+        // two byte size
+        bytes.extend_from_slice(&[0x40, 0x04]);
+        bytes.extend_from_slice(b"webm");
+        assert_eq!(doc_type(&bytes), Some(&b"webm"[..]));
+    }
+}
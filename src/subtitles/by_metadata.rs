@@ -1,27 +1,30 @@
-use super::{Subtitle, DEFAULT_LANG};
-use crate::opensubs;
+use super::{language_ids, Subtitle};
+use crate::{auth::Paired, opensubs, settings::SettingsStore};
 use anyhow::Error;
 use log::info;
 use percent_encoding::NON_ALPHANUMERIC;
-use rocket::{get, response::Debug, serde::json::Json};
+use rocket::{get, response::Debug, serde::json::Json, State};
 
 #[get("/subtitles/by-metadata?<title>&<season>&<episode>")]
 pub(crate) async fn handler(
+    _paired: Paired,
     title: String,
     season: Option<String>,
     episode: Option<String>,
+    settings: &State<SettingsStore>,
 ) -> Result<Json<Vec<Subtitle>>, Debug<Error>> {
-    let url = format_url(&title, season.as_deref(), episode.as_deref());
+    let languages = language_ids(&settings.get().subtitle_languages);
+    let url = format_url(&title, season.as_deref(), episode.as_deref(), &languages);
     let subtitles = opensubs::download_subtitles(&url).await?;
     info!("found {} subtitles", subtitles.len());
     Ok(Json(subtitles))
 }
 
-fn format_url(title: &str, season: Option<&str>, episode: Option<&str>) -> String {
+fn format_url(title: &str, season: Option<&str>, episode: Option<&str>, languages: &str) -> String {
     let mut url = format!(
         "https://rest.opensubtitles.org/search/query-{}/sublanguageid-{}",
         encode(title),
-        DEFAULT_LANG
+        languages
     );
 
     if let Some(season) = season {
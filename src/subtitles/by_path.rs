@@ -1,34 +1,46 @@
-use super::{Subtitle, DEFAULT_LANG};
-use crate::opensubs;
+use super::{language_ids, Subtitle};
+use crate::{
+    app_result::AppResult, auth::Paired, library::Library, media::MediaRegistry, opensubs,
+    settings::SettingsStore,
+};
 use anyhow::{Context, Error};
 use log::{debug, info};
-use rocket::{get, response::Debug, serde::json::Json};
-use std::{
-    io::SeekFrom,
-    path::{Path, PathBuf},
-};
+use rocket::{get, serde::json::Json, State};
+use std::{io::SeekFrom, path::Path};
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncSeekExt},
 };
 
-#[get("/subtitles/by-path?<path>")]
-pub(crate) async fn handler(path: String) -> Result<Json<Vec<Subtitle>>, Debug<Error>> {
-    let path = canonicalize(&path)?;
+/// Searches by the hash of the file with the media `id`.
+#[get("/subtitles/by-path?<id>")]
+pub(crate) async fn handler(
+    _paired: Paired,
+    id: &str,
+    library: &State<Library>,
+    media: &State<MediaRegistry>,
+    settings: &State<SettingsStore>,
+) -> Result<Option<Json<Vec<Subtitle>>>, AppResult<()>> {
+    let path = match media.path(id) {
+        Some(path) => library.check(&path)?,
+        None => return Ok(None),
+    };
+
+    let subtitles = search(&path, &settings.get().subtitle_languages).await?;
+    Ok(Some(Json(subtitles)))
+}
+
+/// Searches for subtitles in the `languages` by the hash of the file at `path`.
+pub(crate) async fn search(path: &Path, languages: &[String]) -> Result<Vec<Subtitle>, Error> {
     info!("loading subtitles for {}", path.display());
     let mut file = open_file(&path).await?;
     let size = file_size(&file).await?;
     let hash = create_hash(&mut file, size).await?;
-    let url = format_url(size, &hash);
+    let url = format_url(size, &hash, &language_ids(languages));
     debug!("file size: {}, hash: {}", size, hash);
     let subtitles = opensubs::download_subtitles(&url).await?;
     info!("found {} subtitles", subtitles.len());
-    Ok(Json(subtitles))
-}
-
-fn canonicalize<P: AsRef<Path>>(path: &P) -> Result<PathBuf, Error> {
-    dunce::canonicalize(path)
-        .with_context(|| format!("failed to canonicalize path: {:#?}", path.as_ref()))
+    Ok(subtitles)
 }
 
 async fn open_file<P: AsRef<Path>>(path: &P) -> Result<File, Error> {
@@ -70,9 +82,9 @@ async fn create_hash(file: &mut File, size: u64) -> Result<String, Error> {
     Ok(format!("{:01$x}", hash, 16))
 }
 
-fn format_url(size: u64, hash: &str) -> String {
+fn format_url(size: u64, hash: &str, languages: &str) -> String {
     format!(
         "https://rest.opensubtitles.org/search/moviebytesize-{}/moviehash-{}/sublanguageid-{}",
-        size, hash, DEFAULT_LANG
+        size, hash, languages
     )
 }
@@ -3,7 +3,14 @@ pub(crate) mod by_path;
 
 use serde::Serialize;
 
-const DEFAULT_LANG: &str = "eng";
+/// The `sublanguageid` of OpenSubtitles searches, e.g. "dan,eng".
+fn language_ids(languages: &[String]) -> String {
+    if languages.is_empty() {
+        "all".to_owned()
+    } else {
+        languages.join(",")
+    }
+}
 
 #[derive(Serialize)]
 pub(crate) struct Subtitle {
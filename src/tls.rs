@@ -0,0 +1,137 @@
+//! Optional HTTPS listener for browsers on other devices, so the session
+//! token isn't sent in the clear. The certificate is self-signed, so users
+//! compare its fingerprint with the one shown in the UI on this machine.
+//! Receivers only get plain HTTP URLs, since they don't trust the certificate.
+use crate::app_result::AppResult;
+use rocket::{get, State};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct HttpsConfig {
+    /// Port of the HTTPS listener, which is off if none is given.
+    #[serde(default)]
+    pub(crate) https_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub(crate) struct TlsInfo {
+    pub(crate) port: Option<u16>,
+
+    /// Port of the HTTP listener, which media URLs for receivers point to.
+    pub(crate) http_port: u16,
+
+    /// SHA-256 fingerprint of the certificate, e.g. `AB:CD:...`.
+    pub(crate) fingerprint: Option<String>,
+}
+
+#[get("/tls")]
+pub(crate) async fn handler(info: &State<TlsInfo>) -> AppResult<TlsInfo> {
+    AppResult::success(info.inner().clone())
+}
+
+#[cfg(feature = "tls")]
+pub(crate) use certificate::load_or_create;
+
+#[cfg(feature = "tls")]
+mod certificate {
+    use crate::ip;
+    use anyhow::{Context, Result};
+    use log::info;
+    use rcgen::{CertificateParams, DnType, SanType};
+    use ring::digest::{digest, SHA256};
+    use std::path::{Path, PathBuf};
+    use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+    const CERT_FILE: &str = "cert.pem";
+    const KEY_FILE: &str = "key.pem";
+
+    /// Paths of the PEM files Rocket reads and the certificate fingerprint.
+    #[derive(Debug)]
+    pub(crate) struct Certificate {
+        pub(crate) cert: PathBuf,
+        pub(crate) key: PathBuf,
+        pub(crate) fingerprint: String,
+    }
+
+    /// Loads the certificate in `dir`, generating one on first run.
+    pub(crate) async fn load_or_create(dir: &Path) -> Result<Certificate> {
+        let cert = dir.join(CERT_FILE);
+        let key = dir.join(KEY_FILE);
+
+        if !cert.exists() || !key.exists() {
+            create(&cert, &key).await?;
+        }
+
+        let pem = tokio::fs::read(&cert)
+            .await
+            .with_context(|| format!("failed to read {}", cert.display()))?;
+
+        let der = pem::parse(pem).context("invalid certificate")?.contents;
+        let fingerprint = fingerprint(&der);
+        info!("tls certificate fingerprint: {}", fingerprint);
+
+        Ok(Certificate {
+            cert,
+            key,
+            fingerprint,
+        })
+    }
+
+    async fn create(cert: &Path, key: &Path) -> Result<()> {
+        let mut params = CertificateParams::new(vec!["localhost".to_owned()]);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Videocaster");
+
+        // the address other devices use, it may change, but the fingerprint won't
+        if let Ok(ip) = ip::get_local_ip() {
+            params.subject_alt_names.push(SanType::IpAddress(ip));
+        }
+
+        let generated = rcgen::Certificate::from_params(params)?;
+
+        if let Some(dir) = cert.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        tokio::fs::write(cert, generated.serialize_pem()?).await?;
+        write_private(key, generated.serialize_private_key_pem().as_bytes()).await?;
+
+        info!("generated tls certificate {}", cert.display());
+        Ok(())
+    }
+
+    /// Writes the key so only the user can read it, from the start rather
+    /// than restricting it afterwards. An old key is removed first, since
+    /// the mode only applies to new files.
+    async fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+        if path.exists() {
+            tokio::fs::remove_file(path).await?;
+        }
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options
+            .open(path)
+            .await
+            .with_context(|| format!("failed to create {}", path.display()))?;
+
+        file.write_all(contents).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    fn fingerprint(der: &[u8]) -> String {
+        digest(&SHA256, der)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
@@ -4,14 +4,84 @@ export interface AppResult<T> {
     error: string | null;
 }
 
+export interface AuthStatus {
+    paired: boolean;
+    local: boolean;
+}
+
+export interface TlsInfo {
+    port: number | null;
+    httpPort: number;
+    fingerprint: string | null;
+}
+
+export async function getAuthStatusAsync(): Promise<AppResult<AuthStatus>> {
+    return fetch("/auth/status").then(res => res.json());
+}
+
+export async function getPinAsync(): Promise<AppResult<string>> {
+    return fetch("/auth/pin").then(res => res.json());
+}
+
+export async function getTlsInfoAsync(): Promise<AppResult<TlsInfo>> {
+    return fetch("/tls").then(res => res.json());
+}
+
+export async function pairAsync(pin: string): Promise<AppResult<string>> {
+    return fetch("/auth/pair", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ pin }),
+    }).then(res => res.json());
+}
+
+// signed token for media urls sent to the receiver, which can't pair
+export async function getMediaTokenAsync(
+    subject: string
+): Promise<AppResult<string>> {
+    const query = `?subject=${encodeURIComponent(subject)}`;
+    return fetch(`/auth/token${query}`).then(res => res.json());
+}
+
 export async function getLocalIpAsync(): Promise<string> {
     return fetch('/ip').then(res => res.json());
 }
 
+export type Verdict =
+    | "directPlay"
+    | "remux"
+    | "transcodeAudio"
+    | "downscale"
+    | "transcode";
+
+export interface MediaInfo {
+    duration: number | null;
+    verdict: Verdict;
+}
+
+// how the file can be played on the receiver, whose profile is looked
+// up by its name
+export async function probeAsync(
+    id: string,
+    device: string
+): Promise<AppResult<MediaInfo>> {
+    const query = `?id=${id}&device=${encodeURIComponent(device)}`;
+    return fetch(`/probe${query}`).then(res => res.json());
+}
+
+// where a stream from `url` starts in the file, which is before the
+// requested time when streams are copied, since copies start at a keyframe
+export async function getStartTimeAsync(url: string): Promise<number | null> {
+    const res = await fetch(url, { method: "HEAD" });
+    const header = res.headers.get("X-Start-Time");
+    return header ? parseFloat(header) : null;
+}
+
 export interface DirectoryItem {
     isDir: boolean;
     name: string;
     path: string;
+    id: string | null;
 }
 
 export interface Directory {
@@ -52,15 +122,15 @@ export async function searchSubsByMetadataAsync(
 }
 
 export async function searchSubsByPath(
-    path: string
+    id: string
 ): Promise<Subtitle[]> {
-    return fetch(`/subtitles/by-path?path=${path}`).then(res => res.json());
+    return fetch(`/subtitles/by-path?id=${id}`).then(res => res.json());
 }
 
 export async function getVideoFrame(
-    path: string
+    id: string
 ): Promise<string> {
-    const res = await fetch(`/frame?path=${path}`);
+    const res = await fetch(`/frame?id=${id}`);
     const blob = await res.blob();
     const reader = new FileReader();
     const promise = new Promise<string>(resolve =>